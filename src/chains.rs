@@ -0,0 +1,178 @@
+use crate::commands::utils::get_stealth_dir;
+use crate::error::CliError;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+const BUNDLED_CHAINS: &str = include_str!("chains.toml");
+const USER_CHAINS_FILE: &str = "chains.toml";
+
+#[derive(Deserialize)]
+struct ChainsFile {
+    #[serde(default)]
+    chain: Vec<Chain>,
+}
+
+/// A StealthGasStation deployment and its defaults on one network
+#[derive(Deserialize, Clone)]
+pub struct Chain {
+    pub chain_id: u64,
+    pub name: Option<String>,
+    pub rpc_url: Option<String>,
+    pub gas_station_address: Option<String>,
+    pub coordinator_url: Option<String>,
+    pub coordinator_pubkey: Option<String>,
+    pub default_tickets: Option<usize>,
+}
+
+impl Chain {
+    /// Fill in every field set in `other`, keeping the current value otherwise
+    fn merge(&mut self, other: Chain) {
+        self.name = other.name.or(self.name.take());
+        self.rpc_url = other.rpc_url.or(self.rpc_url.take());
+        self.gas_station_address = other
+            .gas_station_address
+            .or(self.gas_station_address.take());
+        self.coordinator_url = other.coordinator_url.or(self.coordinator_url.take());
+        self.coordinator_pubkey = other.coordinator_pubkey.or(self.coordinator_pubkey.take());
+        self.default_tickets = other.default_tickets.or(self.default_tickets);
+    }
+
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{} ({})", name, self.chain_id),
+            None => self.chain_id.to_string(),
+        }
+    }
+
+    pub fn require<T: Clone>(&self, field: &str, value: &Option<T>) -> Result<T, CliError> {
+        value.clone().ok_or_else(|| {
+            CliError::Config(format!(
+                "Chain {} has no default {} configured (pass it explicitly or set it in ~/.stealthereum/{})",
+                self.label(),
+                field,
+                USER_CHAINS_FILE
+            ))
+        })
+    }
+}
+
+/// Load the bundled chain registry merged with the user's ~/.stealthereum/chains.toml
+pub fn registry() -> Result<Vec<Chain>, CliError> {
+    registry_at(&get_stealth_dir()?.join(USER_CHAINS_FILE))
+}
+
+fn registry_at(user_path: &Path) -> Result<Vec<Chain>, CliError> {
+    let mut chains = toml::from_str::<ChainsFile>(BUNDLED_CHAINS)?.chain;
+
+    if user_path.exists() {
+        let user_chains: ChainsFile =
+            toml::from_str(&fs::read_to_string(user_path)?).map_err(|e| {
+                CliError::Config(format!(
+                    "Invalid chain registry {}: {}",
+                    user_path.display(),
+                    e
+                ))
+            })?;
+        for chain in user_chains.chain {
+            match chains.iter_mut().find(|c| c.chain_id == chain.chain_id) {
+                Some(existing) => existing.merge(chain),
+                None => chains.push(chain),
+            }
+        }
+    }
+
+    Ok(chains)
+}
+
+/// Look up a chain in the registry, failing with the list of supported networks if unknown
+pub fn lookup(chain_id: u64) -> Result<Chain, CliError> {
+    find(registry()?, chain_id)
+}
+
+fn find(chains: Vec<Chain>, chain_id: u64) -> Result<Chain, CliError> {
+    if let Some(chain) = chains.iter().find(|c| c.chain_id == chain_id) {
+        return Ok(chain.clone());
+    }
+    let supported: Vec<String> = chains.iter().map(|c| c.label()).collect();
+    Err(CliError::UnsupportedChain(format!(
+        "Unsupported chain ID: {}. Supported networks: {} (add more in ~/.stealthereum/{})",
+        chain_id,
+        supported.join(", "),
+        USER_CHAINS_FILE
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::temp_wallet;
+
+    const HOLESKY: u64 = 17000;
+
+    /// Registry merged with a user chains.toml holding `contents`
+    fn registry_with(contents: &str) -> Result<Vec<Chain>, CliError> {
+        let dir = temp_wallet().parent().unwrap().to_path_buf();
+        let user_path = dir.join(USER_CHAINS_FILE);
+        fs::write(&user_path, contents).unwrap();
+        let chains = registry_at(&user_path);
+        fs::remove_dir_all(dir).unwrap();
+        chains
+    }
+
+    #[test]
+    fn user_chains_override_bundled_ones_field_by_field() {
+        let bundled = find(registry_at(Path::new("missing.toml")).unwrap(), HOLESKY).unwrap();
+        let chains = registry_with(
+            r#"
+            [[chain]]
+            chain_id = 17000
+            rpc_url = "https://holesky.example"
+            default_tickets = 3
+
+            [[chain]]
+            chain_id = 5
+            name = "goerli"
+            gas_station_address = "0x01"
+            "#,
+        )
+        .unwrap();
+
+        let holesky = find(chains.clone(), HOLESKY).unwrap();
+        assert_eq!(holesky.rpc_url.as_deref(), Some("https://holesky.example"));
+        assert_eq!(holesky.default_tickets, Some(3));
+        assert_eq!(holesky.name, bundled.name);
+        assert_eq!(holesky.gas_station_address, bundled.gas_station_address);
+        assert_eq!(holesky.coordinator_url, bundled.coordinator_url);
+        assert_eq!(holesky.coordinator_pubkey, bundled.coordinator_pubkey);
+
+        let goerli = find(chains.clone(), 5).unwrap();
+        assert_eq!(goerli.label(), "goerli (5)");
+        assert_eq!(goerli.gas_station_address.as_deref(), Some("0x01"));
+        assert!(matches!(
+            goerli.require("RPC URL", &goerli.rpc_url),
+            Err(CliError::Config(_))
+        ));
+        assert_eq!(chains.len(), 2);
+    }
+
+    #[test]
+    fn unknown_chains_and_invalid_registries_are_rejected() {
+        let chains = registry_at(Path::new("missing.toml")).unwrap();
+        let error = find(chains, 1).err().unwrap();
+        assert!(
+            matches!(&error, CliError::UnsupportedChain(m) if m.contains("holesky (17000)")),
+            "{}",
+            error
+        );
+
+        let error = registry_with("[[chain]]\nname = \"no chain id\"\n")
+            .err()
+            .unwrap();
+        assert!(
+            matches!(&error, CliError::Config(m) if m.contains("Invalid chain registry")),
+            "{}",
+            error
+        );
+    }
+}
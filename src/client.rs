@@ -0,0 +1,183 @@
+use crate::commands::utils::{
+    get_default_contract_address, get_default_pubkey, get_default_rpc, get_default_tickets_number,
+    get_default_url, parse_address, parse_pubkey, parse_url,
+};
+use crate::encryption::Passphrase;
+use crate::error::CliError;
+use crate::store::{StoredTicket, TicketState, TicketStore};
+use alloy::{
+    primitives::Address,
+    providers::{ProviderBuilder, RootProvider},
+    transports::http::{reqwest::Url, Client, Http},
+};
+use eth_stealth_gas_tickets::{TicketsVerifier, UnsignedTicket};
+use std::sync::Arc;
+
+/// Receives progress messages of long running client operations
+pub type Progress = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Chain used when no chain ID is configured
+pub const DEFAULT_CHAIN_ID: u64 = 17000;
+
+/// Connection settings for a StealthGasClient; unset fields fall back to the chain registry
+#[derive(Clone, Default)]
+pub struct ClientConfig {
+    pub chain_id: Option<u64>,
+    pub rpc_url: Option<String>,
+    pub gas_station_address: Option<String>,
+    pub coordinator_url: Option<String>,
+    pub coordinator_pubkey: Option<String>,
+    /// Unlocks the wallet if it is encrypted (opening an encrypted wallet fails if unset)
+    pub passphrase: Passphrase,
+    /// Called with progress messages while waiting on the chain (e.g. `finalize` in watch
+    /// mode); the client never prints anything itself
+    pub progress: Option<Progress>,
+}
+
+/// Typed API over one StealthGasStation deployment and the local ticket wallet.
+///
+/// The `generate`, `buy`, `finalize`, `verify` and `redeem` methods are implemented next to the
+/// matching CLI command in `commands::*` and return the same structured reports the CLI prints.
+pub struct StealthGasClient {
+    chain_id: u64,
+    config: ClientConfig,
+    pub(crate) store: TicketStore,
+}
+
+impl StealthGasClient {
+    /// Create a client backed by the wallet in ~/.stealthereum
+    pub fn new(config: ClientConfig) -> Result<Self, CliError> {
+        let store = TicketStore::open(&config.passphrase)?;
+        Ok(Self::with_store(config, store))
+    }
+
+    /// Create a client for queries that only touch the chain (e.g. `params`), without opening
+    /// the wallet; its ticket store is empty and never saved
+    pub fn without_wallet(config: ClientConfig) -> Self {
+        Self::with_store(config, TicketStore::default())
+    }
+
+    /// Create a client backed by an already opened ticket store
+    pub fn with_store(config: ClientConfig, store: TicketStore) -> Self {
+        StealthGasClient {
+            chain_id: config.chain_id.unwrap_or(DEFAULT_CHAIN_ID),
+            config,
+            store,
+        }
+    }
+
+    /// Forward a progress message to the configured callback, if any
+    pub(crate) fn progress(&self, message: &str) {
+        if let Some(progress) = &self.config.progress {
+            progress(message);
+        }
+    }
+
+    pub fn chain_id(&self) -> u64 {
+        self.chain_id
+    }
+
+    pub fn store(&self) -> &TicketStore {
+        &self.store
+    }
+
+    pub fn rpc_url(&self) -> Result<Url, CliError> {
+        let rpc_url = self
+            .config
+            .rpc_url
+            .clone()
+            .map_or_else(|| get_default_rpc(self.chain_id), Ok)?;
+        parse_url(&rpc_url)
+    }
+
+    /// Read-only provider for the configured RPC endpoint
+    pub fn provider(&self) -> Result<RootProvider<Http<Client>>, CliError> {
+        Ok(ProviderBuilder::new().on_http(self.rpc_url()?))
+    }
+
+    pub fn gas_station_address(&self) -> Result<Address, CliError> {
+        let address = self
+            .config
+            .gas_station_address
+            .clone()
+            .map_or_else(|| get_default_contract_address(self.chain_id), Ok)?;
+        parse_address(&address)
+    }
+
+    pub fn coordinator_url(&self) -> Result<String, CliError> {
+        self.config
+            .coordinator_url
+            .clone()
+            .map_or_else(|| get_default_url(self.chain_id), Ok)
+    }
+
+    pub fn coordinator_pubkey(&self) -> Result<String, CliError> {
+        self.config
+            .coordinator_pubkey
+            .clone()
+            .map_or_else(|| get_default_pubkey(self.chain_id), Ok)
+    }
+
+    pub(crate) fn verifier(&self) -> Result<TicketsVerifier, CliError> {
+        parse_pubkey(&self.coordinator_pubkey()?)
+    }
+
+    pub fn default_tickets(&self) -> Result<usize, CliError> {
+        get_default_tickets_number(self.chain_id)
+    }
+
+    /// Record externally generated unsigned tickets as a batch under the coordinator pubkey
+    pub fn import(&mut self, tickets: Vec<UnsignedTicket>) -> Result<String, CliError> {
+        let pubkey = self.coordinator_pubkey()?;
+        let batch_id = self.store.import_batch(self.chain_id, &pubkey, tickets)?;
+        self.store.save()?;
+        Ok(batch_id)
+    }
+
+    /// Read the unsigned tickets of an input file, rejecting a file that holds none
+    pub fn read_unsigned_tickets(&self, path: &str) -> Result<Vec<UnsignedTicket>, CliError> {
+        let tickets: Vec<UnsignedTicket> = self.store.read_json_file(path)?;
+        if tickets.is_empty() {
+            return Err(CliError::InvalidInput(format!(
+                "Input file {} holds no tickets",
+                path
+            )));
+        }
+        Ok(tickets)
+    }
+
+    /// Import the unsigned tickets of an input file (see `import`) and return the batch id
+    pub fn import_file(&mut self, path: &str) -> Result<String, CliError> {
+        let tickets = self.read_unsigned_tickets(path)?;
+        self.import(tickets)
+    }
+
+    /// Id of the given batch, or of the most recent batch on this chain (and pubkey, if given)
+    /// with a usable ticket
+    pub fn resolve_batch(
+        &self,
+        batch_id: Option<&str>,
+        pubkey: Option<&str>,
+        usable: impl Fn(TicketState) -> bool,
+    ) -> Result<String, CliError> {
+        Ok(self
+            .store
+            .select_batch(batch_id, self.chain_id, pubkey, usable)?
+            .id
+            .clone())
+    }
+
+    /// Like `resolve_batch`, for a `usable` that looks at more of a ticket than its state
+    pub fn resolve_batch_by_ticket(
+        &self,
+        batch_id: Option<&str>,
+        pubkey: Option<&str>,
+        usable: impl Fn(&StoredTicket) -> bool,
+    ) -> Result<String, CliError> {
+        Ok(self
+            .store
+            .select_batch_by_ticket(batch_id, self.chain_id, pubkey, usable)?
+            .id
+            .clone())
+    }
+}
@@ -0,0 +1,245 @@
+use crate::commands::utils::get_stealth_dir;
+use crate::error::CliError;
+use crate::signer::SignerOptions;
+use alloy::{primitives::Address, signers::local::PrivateKeySigner};
+use rand::thread_rng;
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+const KEYSTORE_DIR: &str = "keystores";
+
+#[derive(Serialize)]
+pub struct AccountReport {
+    pub alias: String,
+    pub address: Address,
+    pub path: PathBuf,
+}
+
+impl fmt::Display for AccountReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Account {}: {}", self.alias, self.address)?;
+        writeln!(f, "Keystore: {}", self.path.display())
+    }
+}
+
+#[derive(Serialize)]
+pub struct AccountEntry {
+    pub alias: String,
+    /// Address recorded in the keystore (None for keystores that do not record it)
+    pub address: Option<Address>,
+    pub path: PathBuf,
+}
+
+#[derive(Serialize)]
+pub struct AccountList {
+    pub keystores: PathBuf,
+    pub accounts: Vec<AccountEntry>,
+}
+
+impl fmt::Display for AccountList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.accounts.is_empty() {
+            return writeln!(
+                f,
+                "No accounts in {} (create one with `stealth-gas account new <alias>`)",
+                self.keystores.display()
+            );
+        }
+        for account in &self.accounts {
+            match account.address {
+                Some(address) => writeln!(f, "{}: {}", account.alias, address)?,
+                None => writeln!(f, "{}: (address not recorded)", account.alias)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Directory holding the keystores created by `account new` and `account import`
+pub fn get_keystore_dir() -> Result<PathBuf, CliError> {
+    let dir = get_stealth_dir()?.join(KEYSTORE_DIR);
+    if !dir.exists() {
+        fs::create_dir_all(&dir)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+        }
+    }
+    Ok(dir)
+}
+
+/// Make a new keystore readable by its owner only
+fn restrict_permissions(path: &Path) -> Result<(), CliError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Keystore file for `--account`: an existing path, or the alias of a keystore in
+/// ~/.stealthereum/keystores
+pub fn resolve_account(account: &str) -> Result<PathBuf, CliError> {
+    if Path::new(account).is_file() {
+        return Ok(PathBuf::from(account));
+    }
+    let path = alias_path(account)?;
+    if valid_alias(account) && path.is_file() {
+        return Ok(path);
+    }
+    Err(CliError::InvalidInput(format!(
+        "Account {} is neither a keystore file nor an alias in {} (see `stealth-gas account list`)",
+        account,
+        get_keystore_dir()?.display()
+    )))
+}
+
+/// Create a keystore holding a fresh random key
+pub fn new(alias: String) -> Result<AccountReport, CliError> {
+    let (dir, file_name) = new_keystore_file(&alias)?;
+    let password = new_password()?;
+    let (signer, _) = PrivateKeySigner::new_keystore(
+        &dir,
+        &mut thread_rng(),
+        password.as_bytes(),
+        Some(&file_name),
+    )
+    .map_err(|e| CliError::Storage(format!("Failed to create keystore: {}", e)))?;
+    let path = dir.join(file_name);
+    restrict_permissions(&path)?;
+    Ok(AccountReport {
+        alias,
+        address: signer.address(),
+        path,
+    })
+}
+
+/// Encrypt an existing key (prompted for unless another key source is given) into a keystore
+pub fn import(alias: String, mut signer: SignerOptions) -> Result<AccountReport, CliError> {
+    let (dir, file_name) = new_keystore_file(&alias)?;
+    if !signer.is_set() {
+        signer.private_key = Some(Zeroizing::new(rpassword::prompt_password(
+            "Enter private key:",
+        )?));
+    }
+    let key = signer.load()?;
+    let password = new_password()?;
+    let secret = Zeroizing::new(key.to_bytes().0);
+    PrivateKeySigner::encrypt_keystore(
+        &dir,
+        &mut thread_rng(),
+        secret.as_slice(),
+        password.as_bytes(),
+        Some(&file_name),
+    )
+    .map_err(|e| CliError::Storage(format!("Failed to create keystore: {}", e)))?;
+    let path = dir.join(file_name);
+    restrict_permissions(&path)?;
+    Ok(AccountReport {
+        alias,
+        address: key.address(),
+        path,
+    })
+}
+
+/// Keystores in ~/.stealthereum/keystores with the address each one records
+pub fn list() -> Result<AccountList, CliError> {
+    let keystores = get_keystore_dir()?;
+    let mut accounts = Vec::new();
+    for entry in fs::read_dir(&keystores)? {
+        let path = entry?.path();
+        let Some(alias) = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_suffix(".json"))
+        else {
+            continue;
+        };
+        accounts.push(AccountEntry {
+            alias: alias.to_string(),
+            address: recorded_address(&path),
+            path,
+        });
+    }
+    accounts.sort_by(|a, b| a.alias.cmp(&b.alias));
+    Ok(AccountList {
+        keystores,
+        accounts,
+    })
+}
+
+/// Address of an account (decrypting its keystore if it does not record the address)
+pub fn address(account: String) -> Result<AccountReport, CliError> {
+    let path = resolve_account(&account)?;
+    let address = match recorded_address(&path) {
+        Some(address) => address,
+        None => {
+            let password = Zeroizing::new(rpassword::prompt_password("Enter keystore password:")?);
+            PrivateKeySigner::decrypt_keystore(&path, password.as_bytes())
+                .map_err(|e| CliError::InvalidInput(format!("Failed to unlock keystore: {}", e)))?
+                .address()
+        }
+    };
+    Ok(AccountReport {
+        alias: account,
+        address,
+        path,
+    })
+}
+
+fn alias_path(alias: &str) -> Result<PathBuf, CliError> {
+    Ok(get_keystore_dir()?.join(format!("{}.json", alias)))
+}
+
+fn valid_alias(alias: &str) -> bool {
+    !alias.is_empty()
+        && alias
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Keystore directory and file name for a new alias
+fn new_keystore_file(alias: &str) -> Result<(PathBuf, String), CliError> {
+    if !valid_alias(alias) {
+        return Err(CliError::InvalidInput(format!(
+            "Invalid account alias {}: use letters, digits, '-' and '_'",
+            alias
+        )));
+    }
+    let path = alias_path(alias)?;
+    if path.exists() {
+        return Err(CliError::FileConflict(format!(
+            "Account {} already exists at {}",
+            alias,
+            path.display()
+        )));
+    }
+    Ok((get_keystore_dir()?, format!("{}.json", alias)))
+}
+
+fn new_password() -> Result<Zeroizing<String>, CliError> {
+    let first = Zeroizing::new(rpassword::prompt_password("New keystore password:")?);
+    let second = Zeroizing::new(rpassword::prompt_password("Repeat password:")?);
+    if first != second {
+        return Err(CliError::InvalidInput("Passwords do not match".to_string()));
+    }
+    if first.is_empty() {
+        return Err(CliError::InvalidInput(
+            "The keystore password must not be empty".to_string(),
+        ));
+    }
+    Ok(first)
+}
+
+/// The `address` field geth-compatible keystores record, read without decrypting
+fn recorded_address(path: &Path) -> Option<Address> {
+    let keystore: serde_json::Value = serde_json::from_str(&fs::read_to_string(path).ok()?).ok()?;
+    keystore.get("address")?.as_str()?.parse().ok()
+}
@@ -1,15 +1,28 @@
-use crate::commands::utils::{get_default_contract_address, get_default_rpc};
+use crate::client::{ClientConfig, StealthGasClient};
+use crate::commands::utils::{
+    ensure_new_file, parse_address, parse_json_file, read_input_file, u256_to_eth,
+};
+use crate::encryption::Passphrase;
+use crate::error::CliError;
+use crate::output::{opt_wei, stderr_progress, wei};
+use crate::signer::SignerOptions;
+use crate::store::{normalize_pubkey, now, StoredTicket, TicketState};
 use alloy::{
-    hex,
-    network::EthereumWallet,
-    primitives::{Address, Bytes, U256},
-    providers::ProviderBuilder,
-    signers::local::PrivateKeySigner,
+    consensus::{Transaction, TxEnvelope},
+    eips::eip2718::{Decodable2718, Encodable2718},
+    network::{Ethereum, EthereumWallet, NetworkWallet, TransactionBuilder},
+    primitives::{Address, Bytes, FixedBytes, TxHash, U256},
+    providers::{PendingTransactionBuilder, PendingTransactionError, Provider, ProviderBuilder},
+    rpc::types::TransactionRequest,
     sol,
+    sol_types::SolCall,
+    transports::http::{Client, Http},
 };
 use eth_stealth_gas_tickets::UnsignedTicket;
-use serde_json;
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::fs;
+use std::time::Duration;
 
 sol! {
     #[sol(rpc)]
@@ -20,87 +33,1679 @@ sol! {
     }
 }
 
+/// Tickets of one stored batch bought by a purchase transaction
+#[derive(Serialize)]
+pub struct BatchPurchase {
+    pub batch_id: String,
+    pub ticket_ids: Vec<FixedBytes<32>>,
+}
+
+#[derive(Serialize)]
+pub struct BuyReport {
+    /// Batches whose tickets the transaction bought
+    pub batches: Vec<BatchPurchase>,
+    pub chain_id: u64,
+    pub tickets: usize,
+    pub tx_hash: TxHash,
+    pub status: String,
+    pub block: Option<u64>,
+    pub confirmations: u64,
+    pub gas_used: u64,
+    pub effective_gas_price: u128,
+    /// Transaction fee paid (gas used * effective gas price)
+    #[serde(serialize_with = "wei")]
+    pub fee: U256,
+    /// Value sent for the tickets and shipping
+    #[serde(serialize_with = "wei")]
+    pub total_cost: U256,
+}
+
+impl fmt::Display for BuyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let block = self.block.map_or("unknown".to_string(), |b| b.to_string());
+        match self.batches.as_slice() {
+            [batch] => writeln!(
+                f,
+                "Bought {} tickets of batch {} in block {}",
+                self.tickets, batch.batch_id, block
+            )?,
+            batches => {
+                writeln!(
+                    f,
+                    "Bought {} tickets of {} batches in block {}",
+                    self.tickets,
+                    batches.len(),
+                    block
+                )?;
+                for batch in batches {
+                    writeln!(
+                        f,
+                        "  {}: {} tickets",
+                        batch.batch_id,
+                        batch.ticket_ids.len()
+                    )?;
+                }
+            }
+        }
+        writeln!(f, "Transaction: {}", self.tx_hash)?;
+        writeln!(
+            f,
+            "Status: {} ({} confirmations)",
+            self.status, self.confirmations
+        )?;
+        writeln!(
+            f,
+            "Gas Used: {} at {} wei/gas",
+            self.gas_used, self.effective_gas_price
+        )?;
+        writeln!(f, "Fee: {}", eth_and_wei(self.fee))?;
+        writeln!(f, "Total Cost: {}", eth_and_wei(self.total_cost))
+    }
+}
+
+/// Fee settings of the purchase transaction and how long `buy` waits for it
+#[derive(Clone, Copy)]
+pub struct BuyOptions {
+    /// Blocks to wait for, counting the inclusion block
+    pub confirmations: u64,
+    /// Give up waiting after this long (the tickets stay pending and `finalize` resolves them)
+    pub timeout: Option<Duration>,
+    /// EIP-1559 fee caps in wei per gas (estimated from the fee history if unset)
+    pub max_fee_per_gas: Option<u128>,
+    pub max_priority_fee_per_gas: Option<u128>,
+    /// Gas limit (estimated if unset)
+    pub gas_limit: Option<u64>,
+    /// Nonce, e.g. to replace a stuck transaction (the next nonce of the sender if unset)
+    pub nonce: Option<u64>,
+    /// Abort if tickets + shipping + the maximum gas fee would cost more than this many wei
+    pub max_total_cost: Option<U256>,
+}
+
+impl Default for BuyOptions {
+    fn default() -> Self {
+        BuyOptions {
+            confirmations: 1,
+            timeout: None,
+            max_fee_per_gas: None,
+            max_priority_fee_per_gas: None,
+            gas_limit: None,
+            nonce: None,
+            max_total_cost: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct QuoteReport {
+    /// Stored batches being quoted (tickets read from input files belong to none)
+    pub batches: Vec<String>,
+    pub chain_id: u64,
+    pub tickets: usize,
+    pub from: Address,
+    #[serde(serialize_with = "wei")]
+    pub ticket_cost: U256,
+    #[serde(serialize_with = "wei")]
+    pub shipping_cost: U256,
+    /// Value sent with buyGasTickets
+    #[serde(serialize_with = "wei")]
+    pub value: U256,
+    /// Gas limit (estimated unless given with --gas-limit)
+    pub gas_limit: u64,
+    pub max_fee_per_gas: u128,
+    pub max_priority_fee_per_gas: u128,
+    /// Upper bound of the transaction fee (gas limit * max fee per gas)
+    #[serde(serialize_with = "wei")]
+    pub gas_fee: U256,
+    #[serde(serialize_with = "wei")]
+    pub total_cost: U256,
+    /// Balance of the paying account
+    #[serde(serialize_with = "wei")]
+    pub balance: U256,
+    /// How much the balance falls short of the total cost, if it does
+    #[serde(serialize_with = "opt_wei")]
+    pub shortfall: Option<U256>,
+}
+
+impl QuoteReport {
+    /// Fail unless the paying account can cover the value and the maximum gas fee
+    pub fn ensure_affordable(&self) -> Result<(), CliError> {
+        match self.shortfall {
+            Some(shortfall) => Err(insufficient_funds(
+                self.from,
+                self.balance,
+                self.total_cost,
+                shortfall,
+                &format!(
+                    "value {} + max gas fee {}",
+                    eth_and_wei(self.value),
+                    eth_and_wei(self.gas_fee)
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for QuoteReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.batches.as_slice() {
+            [] => writeln!(
+                f,
+                "Quote for {} tickets (simulated from {}, nothing was sent)",
+                self.tickets, self.from
+            )?,
+            [batch_id] => writeln!(
+                f,
+                "Quote for {} tickets of batch {} (simulated from {}, nothing was sent)",
+                self.tickets, batch_id, self.from
+            )?,
+            batches => writeln!(
+                f,
+                "Quote for {} tickets of batches {} (simulated from {}, nothing was sent)",
+                self.tickets,
+                batches.join(", "),
+                self.from
+            )?,
+        }
+        writeln!(f, "Ticket Cost: {}", eth_and_wei(self.ticket_cost))?;
+        writeln!(f, "Shipping Cost: {}", eth_and_wei(self.shipping_cost))?;
+        writeln!(f, "Value: {}", eth_and_wei(self.value))?;
+        writeln!(
+            f,
+            "Gas: {} at max {} wei/gas (priority {} wei/gas)",
+            self.gas_limit, self.max_fee_per_gas, self.max_priority_fee_per_gas
+        )?;
+        writeln!(f, "Max Gas Fee: {}", eth_and_wei(self.gas_fee))?;
+        writeln!(f, "Total Cost: {}", eth_and_wei(self.total_cost))?;
+        writeln!(f, "Balance: {}", eth_and_wei(self.balance))?;
+        if let Some(shortfall) = self.shortfall {
+            writeln!(f, "Shortfall: {}", eth_and_wei(shortfall))?;
+        }
+        Ok(())
+    }
+}
+
+/// Unsigned buyGasTickets transaction written by `buy build` for signing on an offline machine
+#[derive(Serialize, Deserialize)]
+pub struct UnsignedPurchase {
+    pub chain_id: u64,
+    pub batch_id: String,
+    pub ticket_ids: Vec<FixedBytes<32>>,
+    pub from: Address,
+    /// StealthGasStation contract
+    pub to: Address,
+    pub value: U256,
+    pub nonce: u64,
+    pub gas_limit: u64,
+    pub max_fee_per_gas: u128,
+    pub max_priority_fee_per_gas: u128,
+    /// ABI-encoded buyGasTickets call
+    pub input: Bytes,
+}
+
+/// Signed purchase transaction written by `buy sign`, ready for `buy broadcast`
+#[derive(Serialize, Deserialize)]
+pub struct SignedPurchase {
+    pub chain_id: u64,
+    pub batch_id: String,
+    pub ticket_ids: Vec<FixedBytes<32>>,
+    pub from: Address,
+    pub tx_hash: TxHash,
+    /// EIP-2718 encoded signed transaction
+    pub raw: Bytes,
+}
+
+impl SignedPurchase {
+    /// Decode the signed transaction, checking it hashes to the recorded tx_hash
+    pub fn envelope(&self) -> Result<TxEnvelope, CliError> {
+        let envelope = TxEnvelope::decode_2718(&mut self.raw.as_ref())
+            .map_err(|e| CliError::InvalidInput(format!("Invalid signed transaction: {}", e)))?;
+        if *envelope.tx_hash() != self.tx_hash {
+            return Err(CliError::InvalidInput(format!(
+                "The signed transaction hashes to {}, not {}",
+                envelope.tx_hash(),
+                self.tx_hash
+            )));
+        }
+        Ok(envelope)
+    }
+
+    /// The chain the transaction is signed for (the `chain_id` field is not signed)
+    pub fn signed_chain_id(&self) -> Result<u64, CliError> {
+        self.envelope()?.chain_id().ok_or_else(|| {
+            CliError::InvalidInput(
+                "The signed transaction has no chain id and could be replayed on any chain"
+                    .to_string(),
+            )
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct BuildReport {
+    pub batch_id: String,
+    pub chain_id: u64,
+    pub tickets: usize,
+    pub from: Address,
+    pub to: Address,
+    pub nonce: u64,
+    #[serde(serialize_with = "wei")]
+    pub value: U256,
+    pub gas_limit: u64,
+    pub max_fee_per_gas: u128,
+    pub max_priority_fee_per_gas: u128,
+    /// Value plus the maximum gas fee
+    #[serde(serialize_with = "wei")]
+    pub total_cost: U256,
+    pub output: String,
+}
+
+impl fmt::Display for BuildReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Unsigned purchase of {} tickets of batch {} written to {}",
+            self.tickets, self.batch_id, self.output
+        )?;
+        writeln!(
+            f,
+            "From {} (nonce {}) to {} on chain {}",
+            self.from, self.nonce, self.to, self.chain_id
+        )?;
+        writeln!(f, "Value: {}", eth_and_wei(self.value))?;
+        writeln!(
+            f,
+            "Gas: {} at max {} wei/gas (priority {} wei/gas)",
+            self.gas_limit, self.max_fee_per_gas, self.max_priority_fee_per_gas
+        )?;
+        writeln!(f, "Total Cost: {}", eth_and_wei(self.total_cost))
+    }
+}
+
+#[derive(Serialize)]
+pub struct SignReport {
+    pub batch_id: String,
+    pub chain_id: u64,
+    pub tickets: usize,
+    pub from: Address,
+    pub to: Address,
+    pub nonce: u64,
+    #[serde(serialize_with = "wei")]
+    pub value: U256,
+    pub tx_hash: TxHash,
+    pub output: String,
+}
+
+impl fmt::Display for SignReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Signed purchase of {} tickets of batch {} written to {}",
+            self.tickets, self.batch_id, self.output
+        )?;
+        writeln!(
+            f,
+            "From {} (nonce {}) to {} on chain {}",
+            self.from, self.nonce, self.to, self.chain_id
+        )?;
+        writeln!(f, "Value: {}", eth_and_wei(self.value))?;
+        writeln!(f, "Transaction: {}", self.tx_hash)
+    }
+}
+
+/// buyGasTickets call to propose from a smart contract wallet, written by `buy calldata`
+#[derive(Serialize)]
+pub struct CalldataReport {
+    pub batch_id: String,
+    pub chain_id: u64,
+    pub tickets: usize,
+    /// StealthGasStation contract
+    pub to: Address,
+    #[serde(serialize_with = "wei")]
+    pub value: U256,
+    /// ABI-encoded buyGasTickets call
+    pub data: Bytes,
+    pub simulated_from: Option<Address>,
+    /// Safe Transaction Builder file written for the call, if any
+    pub safe_json: Option<String>,
+}
+
+impl fmt::Display for CalldataReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Purchase of {} tickets of batch {} on chain {} (tickets marked pending)",
+            self.tickets, self.batch_id, self.chain_id
+        )?;
+        if let Some(sender) = self.simulated_from {
+            writeln!(f, "Simulated from: {}", sender)?;
+        }
+        writeln!(f, "To: {}", self.to)?;
+        writeln!(f, "Value: {}", eth_and_wei(self.value))?;
+        writeln!(f, "Data: {}", self.data)?;
+        if let Some(path) = &self.safe_json {
+            writeln!(f, "Safe Transaction Builder batch written to {}", path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Tickets of an abandoned `buy calldata` proposal made purchasable again by `buy release`
+#[derive(Serialize)]
+pub struct ReleaseReport {
+    pub batch_id: String,
+    pub chain_id: u64,
+    pub tickets: usize,
+}
+
+impl fmt::Display for ReleaseReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Released {} exported tickets of batch {} on chain {}; they can be bought again",
+            self.tickets, self.batch_id, self.chain_id
+        )
+    }
+}
+
+/// Add tickets to the purchase entry of their batch, skipping those already in it
+fn add_tickets(
+    batches: &mut Vec<(String, Vec<UnsignedTicket>)>,
+    batch_id: String,
+    tickets: Vec<UnsignedTicket>,
+) {
+    let index = match batches.iter().position(|(id, _)| *id == batch_id) {
+        Some(index) => index,
+        None => {
+            batches.push((batch_id, Vec::new()));
+            batches.len() - 1
+        }
+    };
+    let selected = &mut batches[index].1;
+    for ticket in tickets {
+        if !selected.iter().any(|t| t.id == ticket.id) {
+            selected.push(ticket);
+        }
+    }
+}
+
+/// Safe Transaction Builder batch file holding a single call
+fn safe_batch(report: &CalldataReport, safe: Option<Address>) -> serde_json::Value {
+    serde_json::json!({
+        "version": "1.0",
+        "chainId": report.chain_id.to_string(),
+        "createdAt": now() * 1000,
+        "meta": {
+            "name": format!("Buy {} stealth gas tickets", report.tickets),
+            "description": format!("buyGasTickets for ticket batch {}", report.batch_id),
+            "createdFromSafeAddress": safe.map(|a| a.to_string()).unwrap_or_default(),
+        },
+        "transactions": [{
+            "to": report.to.to_string(),
+            "value": report.value.to_string(),
+            "data": report.data.to_string(),
+            "contractMethod": null,
+            "contractInputsValues": null,
+        }],
+    })
+}
+
+/// ABI-encoded buyGasTickets call for the given tickets
+fn buy_calldata(unsigned_tickets: &[UnsignedTicket]) -> Bytes {
+    let blinded_messages: Vec<Bytes> = unsigned_tickets
+        .iter()
+        .map(|t| t.blind_msg.clone())
+        .collect();
+    IStealthGasStation::buyGasTicketsCall {
+        blindedMessages: blinded_messages,
+    }
+    .abi_encode()
+    .into()
+}
+
+fn insufficient_funds(
+    from: Address,
+    balance: U256,
+    required: U256,
+    shortfall: U256,
+    breakdown: &str,
+) -> CliError {
+    CliError::InsufficientFunds(format!(
+        "Insufficient funds: {} holds {} but the purchase needs up to {} ({}); short by {}",
+        from,
+        eth_and_wei(balance),
+        eth_and_wei(required),
+        breakdown,
+        eth_and_wei(shortfall)
+    ))
+}
+
+fn eth_and_wei(amount: U256) -> String {
+    format!("{} ({} wei)", u256_to_eth(amount), amount)
+}
+
+impl StealthGasClient {
+    /// Purchasable tickets of a stored batch (the most recent one if none is given)
+    fn purchasable_tickets(
+        &self,
+        batch_id: Option<&str>,
+    ) -> Result<(String, Vec<UnsignedTicket>), CliError> {
+        let batch_id = self.resolve_batch(batch_id, None, |s| s.is_purchasable())?;
+        let unsigned_tickets = self
+            .store
+            .batch(&batch_id)?
+            .unsigned_tickets(|s| s.is_purchasable());
+        if unsigned_tickets.is_empty() {
+            return Err(CliError::InvalidInput(format!(
+                "Batch {} has no tickets left to buy",
+                batch_id
+            )));
+        }
+        Ok((batch_id, unsigned_tickets))
+    }
+
+    /// Purchasable tickets of each given stored batch (of the most recent batch if none is
+    /// given), to be bought together in one transaction
+    fn purchasable_batches(
+        &self,
+        batch_ids: &[String],
+    ) -> Result<Vec<(String, Vec<UnsignedTicket>)>, CliError> {
+        if batch_ids.is_empty() {
+            return Ok(vec![self.purchasable_tickets(None)?]);
+        }
+        let mut batches: Vec<(String, Vec<UnsignedTicket>)> = Vec::new();
+        for batch_id in batch_ids {
+            let (batch_id, tickets) = self.purchasable_tickets(Some(batch_id))?;
+            add_tickets(&mut batches, batch_id, tickets);
+        }
+        Ok(batches)
+    }
+
+    /// The tickets one `buy` (or `buy --dry-run`) purchases, by batch: those listed in each input
+    /// file that are still purchasable (the file is imported into the wallet in memory; the
+    /// purchase saves it) plus the purchasable tickets of each given batch, or of every pending
+    /// batch with `all`. Without inputs or batches, the most recent batch is bought. A ticket
+    /// named more than once is bought once
+    pub fn select_purchase(
+        &mut self,
+        inputs: &[String],
+        batch_ids: &[String],
+        all: bool,
+    ) -> Result<Vec<(String, Vec<UnsignedTicket>)>, CliError> {
+        if all {
+            return self.purchasable_batches(&self.pending_batches()?);
+        }
+        let mut batches: Vec<(String, Vec<UnsignedTicket>)> = Vec::new();
+        for input_path in inputs {
+            let tickets = self.read_unsigned_tickets(input_path)?;
+            let pubkey = self.coordinator_pubkey()?;
+            let batch_id = self
+                .store
+                .import_batch(self.chain_id(), &pubkey, tickets.clone())?;
+
+            // Only the file's tickets, even if the stored batch holding them has more
+            let batch = self.store.batch(&batch_id)?;
+            let purchasable: Vec<UnsignedTicket> = tickets
+                .iter()
+                .filter_map(|t| batch.tickets.iter().find(|s| s.unsigned.id == t.id))
+                .filter(|s| s.state.is_purchasable())
+                .map(|s| s.unsigned.clone())
+                .collect();
+            if purchasable.is_empty() {
+                return Err(CliError::InvalidInput(format!(
+                    "Input file {} has no tickets left to buy",
+                    input_path
+                )));
+            }
+            add_tickets(&mut batches, batch_id, purchasable);
+        }
+        if inputs.is_empty() || !batch_ids.is_empty() {
+            for (batch_id, tickets) in self.purchasable_batches(batch_ids)? {
+                add_tickets(&mut batches, batch_id, tickets);
+            }
+        }
+        Ok(batches)
+    }
+
+    /// Check that every ticket of a purchase is stored in its batch and still purchasable, and
+    /// that all batches were blinded for the same coordinator (one buyGasTickets call pays one)
+    fn validate_purchase(&self, batches: &[(String, Vec<UnsignedTicket>)]) -> Result<(), CliError> {
+        let Some((first, _)) = batches.first() else {
+            return Err(CliError::InvalidInput("No tickets to buy".to_string()));
+        };
+        let pubkey = &self.store.batch(first)?.pubkey;
+        for (batch_id, tickets) in batches {
+            let batch = self.store.batch(batch_id)?;
+            if batch.pubkey != *pubkey {
+                return Err(CliError::InvalidInput(format!(
+                    "Batches {} and {} were generated for different coordinator keys and cannot be bought together",
+                    first, batch_id
+                )));
+            }
+            if batch.chain_id != self.chain_id() {
+                return Err(CliError::InvalidInput(format!(
+                    "Batch {} belongs to chain {}, not chain {}",
+                    batch_id,
+                    batch.chain_id,
+                    self.chain_id()
+                )));
+            }
+            for ticket in tickets {
+                if !batch
+                    .tickets
+                    .iter()
+                    .any(|t| t.unsigned.id == ticket.id && t.state.is_purchasable())
+                {
+                    return Err(CliError::InvalidInput(format!(
+                        "Ticket {} is not a purchasable ticket of batch {}",
+                        ticket.id, batch_id
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Ids of the stored batches on this chain, generated for the coordinator pubkey, that still
+    /// have tickets to buy (oldest first)
+    pub fn pending_batches(&self) -> Result<Vec<String>, CliError> {
+        let pubkey = normalize_pubkey(&self.coordinator_pubkey()?);
+        let batch_ids: Vec<String> = self
+            .store
+            .batches
+            .iter()
+            .filter(|b| b.chain_id == self.chain_id() && b.pubkey == pubkey)
+            .filter(|b| b.tickets.iter().any(|t| t.state.is_purchasable()))
+            .map(|b| b.id.clone())
+            .collect();
+        if batch_ids.is_empty() {
+            return Err(CliError::InvalidInput(format!(
+                "No batches with tickets left to buy for chain {} in {}",
+                self.chain_id(),
+                self.store.path().display()
+            )));
+        }
+        Ok(batch_ids)
+    }
+
+    /// Quote buying the purchasable tickets of a stored batch without sending anything
+    pub async fn quote(
+        &self,
+        from: Address,
+        batch_id: Option<&str>,
+        options: &BuyOptions,
+    ) -> Result<QuoteReport, CliError> {
+        let batch_ids: Vec<String> = batch_id.map(str::to_string).into_iter().collect();
+        self.quote_batches(from, &batch_ids, options).await
+    }
+
+    /// Quote buying the purchasable tickets of several stored batches in one transaction
+    pub async fn quote_batches(
+        &self,
+        from: Address,
+        batch_ids: &[String],
+        options: &BuyOptions,
+    ) -> Result<QuoteReport, CliError> {
+        self.quote_purchase(from, &self.purchasable_batches(batch_ids)?, options)
+            .await
+    }
+
+    /// Quote buying the given tickets, by stored batch (e.g. from `select_purchase`), in one
+    /// transaction
+    pub async fn quote_purchase(
+        &self,
+        from: Address,
+        batches: &[(String, Vec<UnsignedTicket>)],
+        options: &BuyOptions,
+    ) -> Result<QuoteReport, CliError> {
+        self.validate_purchase(batches)?;
+        let unsigned_tickets: Vec<UnsignedTicket> = batches
+            .iter()
+            .flat_map(|(_, t)| t.iter().cloned())
+            .collect();
+        let mut report = self.quote_tickets(from, &unsigned_tickets, options).await?;
+        report.batches = batches.iter().map(|(id, _)| id.clone()).collect();
+        Ok(report)
+    }
+
+    /// Simulate buyGasTickets for the given tickets with eth_call, then estimate its gas and
+    /// EIP-1559 fees (unless set in `options`) to get the all-in cost, enforcing max_total_cost.
+    /// Fails early if `from` cannot pay the value; a shortfall on gas is only reported
+    pub async fn quote_tickets(
+        &self,
+        from: Address,
+        unsigned_tickets: &[UnsignedTicket],
+        options: &BuyOptions,
+    ) -> Result<QuoteReport, CliError> {
+        let provider = self.provider()?;
+        let contract = IStealthGasStation::new(self.gas_station_address()?, &provider);
+
+        let ticket_cost = contract.ticketCost().call().await?._0;
+        let shipping_cost = contract.shippingCost().call().await?._0;
+        let value = ticket_cost * U256::from(unsigned_tickets.len()) + shipping_cost;
+
+        // The call cannot even be simulated if the account cannot pay the value
+        let balance = provider.get_balance(from).await?;
+        if balance < value {
+            return Err(insufficient_funds(
+                from,
+                balance,
+                value,
+                value - balance,
+                "value only, before gas",
+            ));
+        }
+
+        let blinded_messages: Vec<Bytes> = unsigned_tickets
+            .iter()
+            .map(|t| t.blind_msg.clone())
+            .collect();
+        let call = contract
+            .buyGasTickets(blinded_messages)
+            .value(value)
+            .from(from);
+        call.call().await.map_err(|e| {
+            CliError::TransactionFailed(format!("Simulated buyGasTickets call failed: {}", e))
+        })?;
+        let gas_limit = match options.gas_limit {
+            Some(gas_limit) => gas_limit,
+            None => call.estimate_gas().await?,
+        };
+        let (max_fee_per_gas, max_priority_fee_per_gas) =
+            match (options.max_fee_per_gas, options.max_priority_fee_per_gas) {
+                (Some(max_fee), Some(priority_fee)) => (max_fee, priority_fee),
+                (max_fee, priority_fee) => {
+                    let fees = provider.estimate_eip1559_fees(None).await?;
+                    let max_fee = max_fee.unwrap_or(fees.max_fee_per_gas);
+                    (
+                        max_fee,
+                        priority_fee.unwrap_or(fees.max_priority_fee_per_gas.min(max_fee)),
+                    )
+                }
+            };
+        // A given priority fee must also fit under an estimated max fee, or nodes reject the tx
+        if max_priority_fee_per_gas > max_fee_per_gas {
+            let max_fee = match options.max_fee_per_gas {
+                Some(_) => "--max-fee-per-gas",
+                None => "the estimated max fee per gas (raise it with --max-fee-per-gas)",
+            };
+            return Err(CliError::InvalidInput(format!(
+                "--max-priority-fee ({} wei) must not exceed {} ({} wei)",
+                max_priority_fee_per_gas, max_fee, max_fee_per_gas
+            )));
+        }
+
+        let gas_fee = U256::from(gas_limit) * U256::from(max_fee_per_gas);
+        let total_cost = value + gas_fee;
+        if let Some(max_total_cost) = options.max_total_cost {
+            if total_cost > max_total_cost {
+                return Err(CliError::InvalidInput(format!(
+                    "Aborting: the purchase could cost up to {} (value {} + max gas fee {}), above --max-total-cost {}",
+                    eth_and_wei(total_cost),
+                    eth_and_wei(value),
+                    eth_and_wei(gas_fee),
+                    eth_and_wei(max_total_cost)
+                )));
+            }
+        }
+
+        Ok(QuoteReport {
+            batches: Vec::new(),
+            chain_id: self.chain_id(),
+            tickets: unsigned_tickets.len(),
+            from,
+            ticket_cost,
+            shipping_cost,
+            value,
+            gas_limit,
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+            gas_fee,
+            total_cost,
+            balance,
+            shortfall: (balance < total_cost).then(|| total_cost - balance),
+        })
+    }
+
+    /// Buy the purchasable tickets of a stored batch (the most recent one if none is given),
+    /// paying with the given wallet, and wait for the purchase transaction to be confirmed
+    pub async fn buy(
+        &mut self,
+        wallet: EthereumWallet,
+        batch_id: Option<&str>,
+        options: BuyOptions,
+    ) -> Result<BuyReport, CliError> {
+        let batch_ids: Vec<String> = batch_id.map(str::to_string).into_iter().collect();
+        self.buy_batches(wallet, &batch_ids, options).await
+    }
+
+    /// Buy the purchasable tickets of several stored batches with a single buyGasTickets
+    /// transaction, recording it against the tickets of each batch
+    pub async fn buy_batches(
+        &mut self,
+        wallet: EthereumWallet,
+        batch_ids: &[String],
+        options: BuyOptions,
+    ) -> Result<BuyReport, CliError> {
+        let batches = self.purchasable_batches(batch_ids)?;
+        self.buy_tickets(wallet, batches, options).await
+    }
+
+    /// Buy the given tickets, by stored batch (e.g. from `select_purchase`), with a single
+    /// buyGasTickets transaction
+    pub async fn buy_tickets(
+        &mut self,
+        wallet: EthereumWallet,
+        batches: Vec<(String, Vec<UnsignedTicket>)>,
+        options: BuyOptions,
+    ) -> Result<BuyReport, CliError> {
+        self.validate_purchase(&batches)?;
+        let unsigned_tickets: Vec<UnsignedTicket> = batches
+            .iter()
+            .flat_map(|(_, t)| t.iter().cloned())
+            .collect();
+
+        // Simulate first, so a reverting or too expensive purchase never reaches the chain
+        let from = NetworkWallet::<Ethereum>::default_signer_address(&wallet);
+        let quote = self
+            .quote_tickets(from, &unsigned_tickets, &options)
+            .await?;
+        quote.ensure_affordable()?;
+        let total_cost = quote.value;
+
+        // Set up the signing provider and contract instance
+        let signer_provider = ProviderBuilder::new()
+            .with_recommended_fillers()
+            .wallet(wallet)
+            .on_http(self.rpc_url()?);
+        let contract = IStealthGasStation::new(self.gas_station_address()?, signer_provider);
+
+        // Prepare blinded messages for contract call
+        let blinded_messages: Vec<Bytes> = unsigned_tickets
+            .iter()
+            .map(|t| t.blind_msg.clone())
+            .collect();
+
+        // Create and send transaction with the quoted gas limit and fee caps
+        let mut call = contract
+            .buyGasTickets(blinded_messages)
+            .value(total_cost)
+            .gas(quote.gas_limit)
+            .max_fee_per_gas(quote.max_fee_per_gas)
+            .max_priority_fee_per_gas(quote.max_priority_fee_per_gas);
+        if let Some(nonce) = options.nonce {
+            call = call.nonce(nonce);
+        }
+        let tx = call.send().await?;
+        self.await_purchase(tx, &batches, total_cost, &options)
+            .await
+    }
+
+    /// Build the unsigned purchase transaction of a stored batch (the most recent one if none is
+    /// given) for `from` to sign elsewhere, after simulating it like `quote`
+    pub async fn build_purchase(
+        &self,
+        from: Address,
+        batch_id: Option<&str>,
+        options: &BuyOptions,
+    ) -> Result<UnsignedPurchase, CliError> {
+        let (batch_id, unsigned_tickets) = self.purchasable_tickets(batch_id)?;
+        let quote = self.quote_tickets(from, &unsigned_tickets, options).await?;
+        quote.ensure_affordable()?;
+        let nonce = match options.nonce {
+            Some(nonce) => nonce,
+            None => {
+                self.provider()?
+                    .get_transaction_count(from)
+                    .pending()
+                    .await?
+            }
+        };
+
+        Ok(UnsignedPurchase {
+            chain_id: self.chain_id(),
+            batch_id,
+            ticket_ids: unsigned_tickets.iter().map(|t| t.id).collect(),
+            from,
+            to: self.gas_station_address()?,
+            value: quote.value,
+            nonce,
+            gas_limit: quote.gas_limit,
+            max_fee_per_gas: quote.max_fee_per_gas,
+            max_priority_fee_per_gas: quote.max_priority_fee_per_gas,
+            input: buy_calldata(&unsigned_tickets),
+        })
+    }
+
+    /// Prepare the purchase of a stored batch (the most recent one with tickets to buy or
+    /// exported if none is given) for a smart contract wallet: return the buyGasTickets call to propose and mark the tickets as
+    /// pending. Tickets of an earlier export are included again, so a lost proposal can be
+    /// recreated. The call is simulated first if the wallet's address is given
+    pub async fn export_purchase(
+        &mut self,
+        batch_id: Option<&str>,
+        sender: Option<Address>,
+    ) -> Result<CalldataReport, CliError> {
+        // A batch whose purchase was sent as a transaction is not exported again
+        let batch_id = self.resolve_batch_by_ticket(batch_id, None, |t| {
+            t.state.is_purchasable() || t.is_exported_purchase()
+        })?;
+        let unsigned_tickets: Vec<UnsignedTicket> = self
+            .store
+            .batch(&batch_id)?
+            .tickets
+            .iter()
+            .filter(|t| t.state.is_purchasable() || t.is_exported_purchase())
+            .map(|t| t.unsigned.clone())
+            .collect();
+        if unsigned_tickets.is_empty() {
+            return Err(CliError::InvalidInput(format!(
+                "Batch {} has no tickets left to buy",
+                batch_id
+            )));
+        }
+        let to = self.gas_station_address()?;
+        let value = match sender {
+            Some(sender) => {
+                self.quote_tickets(sender, &unsigned_tickets, &BuyOptions::default())
+                    .await?
+                    .value
+            }
+            None => {
+                let provider = self.provider()?;
+                let contract = IStealthGasStation::new(to, &provider);
+                let ticket_cost = contract.ticketCost().call().await?._0;
+                let shipping_cost = contract.shippingCost().call().await?._0;
+                ticket_cost * U256::from(unsigned_tickets.len()) + shipping_cost
+            }
+        };
+
+        // There is no transaction hash until the wallet executes the call; finalize picks the
+        // tickets up from the coordinator's signatures
+        let batch = self.store.batch_mut(&batch_id)?;
+        for unsigned in &unsigned_tickets {
+            if let Some(ticket) = batch.ticket_mut(&unsigned.id) {
+                ticket.transition(TicketState::PurchaseTxPending, None, None)?;
+            }
+        }
+        self.store.save()?;
+
+        Ok(CalldataReport {
+            batch_id,
+            chain_id: self.chain_id(),
+            tickets: unsigned_tickets.len(),
+            to,
+            value,
+            data: buy_calldata(&unsigned_tickets),
+            simulated_from: sender,
+            safe_json: None,
+        })
+    }
+
+    /// Make the tickets of a stored batch exported with `buy calldata` (the most recent such batch
+    /// if none is given) purchasable again, after their proposal was rejected or lost
+    pub fn release_purchase(&mut self, batch_id: Option<&str>) -> Result<ReleaseReport, CliError> {
+        let batch_id =
+            self.resolve_batch_by_ticket(batch_id, None, StoredTicket::is_exported_purchase)?;
+        let batch = self.store.batch_mut(&batch_id)?;
+        let mut tickets = 0;
+        for ticket in batch
+            .tickets
+            .iter_mut()
+            .filter(|t| t.is_exported_purchase())
+        {
+            ticket.transition(TicketState::Failed, None, None)?;
+            tickets += 1;
+        }
+        if tickets == 0 {
+            return Err(CliError::InvalidInput(format!(
+                "Batch {} has no tickets exported with `buy calldata` (pending transactions are replaced with `stealth-gas tx`)",
+                batch_id
+            )));
+        }
+        self.store.save()?;
+
+        Ok(ReleaseReport {
+            batch_id,
+            chain_id: self.chain_id(),
+            tickets,
+        })
+    }
+
+    /// Submit a purchase signed offline and wait for it like `buy`
+    pub async fn broadcast_purchase(
+        &mut self,
+        signed: &SignedPurchase,
+        options: &BuyOptions,
+    ) -> Result<BuyReport, CliError> {
+        // Only the raw transaction is signed: its chain and sender are checked, not the file's
+        let envelope = signed.envelope()?;
+        let signed_chain_id = signed.signed_chain_id()?;
+        if signed_chain_id != self.chain_id() {
+            return Err(CliError::InvalidInput(format!(
+                "The transaction is signed for chain {}, not chain {}",
+                signed_chain_id,
+                self.chain_id()
+            )));
+        }
+        let sender = envelope
+            .recover_signer()
+            .map_err(|e| CliError::InvalidInput(format!("Invalid transaction signature: {}", e)))?;
+        if sender != signed.from {
+            return Err(CliError::InvalidInput(format!(
+                "The transaction is signed by {}, not {}",
+                sender, signed.from
+            )));
+        }
+
+        // The tickets must still be waiting to be bought in this wallet
+        let batch = self.store.batch(&signed.batch_id)?;
+        let unsigned_tickets: Vec<UnsignedTicket> = signed
+            .ticket_ids
+            .iter()
+            .filter_map(|id| batch.tickets.iter().find(|t| t.unsigned.id == *id))
+            .filter(|t| t.state.is_purchasable())
+            .map(|t| t.unsigned.clone())
+            .collect();
+        if unsigned_tickets.len() != signed.ticket_ids.len() {
+            return Err(CliError::InvalidInput(format!(
+                "Batch {} no longer holds all {} tickets of the transaction as purchasable",
+                signed.batch_id,
+                signed.ticket_ids.len()
+            )));
+        }
+
+        // The transaction itself must buy exactly these tickets from this deployment before
+        // they are marked pending on its hash
+        let gas_station = self.gas_station_address()?;
+        if envelope.to() != Some(gas_station) {
+            return Err(CliError::InvalidInput(format!(
+                "The signed transaction calls {}, not the StealthGasStation contract {}",
+                envelope
+                    .to()
+                    .map_or("no address (contract creation)".to_string(), |to| to
+                        .to_string()),
+                gas_station
+            )));
+        }
+        if *envelope.input() != buy_calldata(&unsigned_tickets) {
+            return Err(CliError::InvalidInput(format!(
+                "The signed transaction does not buy the {} tickets listed in the file",
+                signed.ticket_ids.len()
+            )));
+        }
+
+        let tx = self.provider()?.send_raw_transaction(&signed.raw).await?;
+        self.await_purchase(
+            tx,
+            &[(signed.batch_id.clone(), unsigned_tickets)],
+            envelope.value(),
+            options,
+        )
+        .await
+    }
+
+    /// Record a sent purchase transaction against the tickets of each batch it buys, then wait
+    /// for it to be confirmed and mark the tickets purchased (or failed if it reverted)
+    async fn await_purchase(
+        &mut self,
+        tx: PendingTransactionBuilder<Http<Client>, Ethereum>,
+        batches: &[(String, Vec<UnsignedTicket>)],
+        total_cost: U256,
+        options: &BuyOptions,
+    ) -> Result<BuyReport, CliError> {
+        let chain_id = self.chain_id();
+        let tx_hash = *tx.tx_hash();
+        self.progress(&format!("Transaction sent! Hash: {}", tx_hash));
+
+        // Record the pending purchase against every ticket in the transaction
+        for (batch_id, unsigned_tickets) in batches {
+            let batch = self.store.batch_mut(batch_id)?;
+            for unsigned in unsigned_tickets {
+                if let Some(ticket) = batch.ticket_mut(&unsigned.id) {
+                    ticket.transition(TicketState::PurchaseTxPending, Some(tx_hash), None)?;
+                }
+            }
+        }
+        self.store.save()?;
+
+        // Wait for the receipt so the inclusion block can serve as the finalize start block
+        self.progress(&format!(
+            "Waiting for {} confirmations...",
+            options.confirmations
+        ));
+        let receipt = tx
+            .with_required_confirmations(options.confirmations)
+            .with_timeout(options.timeout)
+            .get_receipt()
+            .await
+            .map_err(|e| match e {
+                PendingTransactionError::TxWatcher(_) => CliError::Timeout(format!(
+                    "Transaction {} not confirmed after {}s; its tickets stay pending (`stealth-gas finalize` picks it up once mined)",
+                    tx_hash,
+                    options.timeout.unwrap_or_default().as_secs()
+                )),
+                e => e.into(),
+            })?;
+        let state = if receipt.status() {
+            TicketState::Purchased
+        } else {
+            TicketState::Failed
+        };
+        for (batch_id, unsigned_tickets) in batches {
+            let batch = self.store.batch_mut(batch_id)?;
+            for unsigned in unsigned_tickets {
+                if let Some(ticket) = batch.ticket_mut(&unsigned.id) {
+                    ticket.transition(state, Some(tx_hash), receipt.block_number)?;
+                }
+            }
+        }
+        self.store.save()?;
+
+        let batch_ids: Vec<&str> = batches.iter().map(|(id, _)| id.as_str()).collect();
+
+        let fee = U256::from(receipt.gas_used) * U256::from(receipt.effective_gas_price);
+        if !receipt.status() {
+            return Err(CliError::TransactionFailed(format!(
+                "Transaction {} reverted in block {} (gas used {}, fee {}); the tickets of batch(es) {} are marked failed",
+                tx_hash,
+                receipt
+                    .block_number
+                    .map_or("unknown".to_string(), |b| b.to_string()),
+                receipt.gas_used,
+                eth_and_wei(fee),
+                batch_ids.join(", ")
+            )));
+        }
+
+        Ok(BuyReport {
+            batches: batches
+                .iter()
+                .map(|(batch_id, unsigned_tickets)| BatchPurchase {
+                    batch_id: batch_id.clone(),
+                    ticket_ids: unsigned_tickets.iter().map(|t| t.id).collect(),
+                })
+                .collect(),
+            chain_id,
+            tickets: batches.iter().map(|(_, t)| t.len()).sum(),
+            tx_hash,
+            status: "success".to_string(),
+            block: receipt.block_number,
+            confirmations: options.confirmations,
+            gas_used: receipt.gas_used,
+            effective_gas_price: receipt.effective_gas_price,
+            fee,
+            total_cost,
+        })
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn run(
     rpc_url: Option<String>,
     contract_address: Option<String>,
-    input: Option<String>,
-    private_key: Option<String>,
-    account: Option<String>,
+    inputs: Vec<String>,
+    batches: Vec<String>,
+    all: bool,
+    signer: SignerOptions,
+    chain_id: Option<u64>,
+    options: BuyOptions,
+    passphrase: Passphrase,
+) -> Result<BuyReport, CliError> {
+    let mut client = StealthGasClient::new(ClientConfig {
+        chain_id,
+        rpc_url,
+        gas_station_address: contract_address,
+        passphrase,
+        progress: stderr_progress(),
+        ..Default::default()
+    })?;
+
+    // Set up the wallet
+    let wallet = signer.wallet().await?;
+
+    let batches = client.select_purchase(&inputs, &batches, all)?;
+    client.buy_tickets(wallet, batches, options).await
+}
+
+/// `buy --dry-run`: quote and simulate the purchase without sending a transaction
+#[allow(clippy::too_many_arguments)]
+pub async fn quote(
+    rpc_url: Option<String>,
+    contract_address: Option<String>,
+    inputs: Vec<String>,
+    batches: Vec<String>,
+    all: bool,
+    signer: SignerOptions,
+    from: Option<String>,
     chain_id: Option<u64>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // Get chain ID and defaults
-    let chain_id = chain_id.unwrap_or(17000);
-
-    // Use provided values or defaults
-    let rpc_url = rpc_url.unwrap_or(get_default_rpc(chain_id));
-    let contract_address = contract_address.unwrap_or(get_default_contract_address(chain_id));
-    let mut input_path = input.unwrap_or_else(|| "".to_string());
-
-    // If input path is empty, use default path in ~/.stealthereum
-    if input_path.is_empty() {
-        let home_dir = dirs::home_dir().expect("Could not find home directory");
-        let stealth_dir = home_dir.join(".stealthereum");
-        input_path = stealth_dir
-            .join(format!("unsigned_tickets_{}.json", chain_id))
-            .to_str()
-            .expect("Failed to convert path to string")
-            .to_string();
-    }
-
-    if private_key.is_none() && account.is_none() {
-        return Err("Either private key or account path must be provided".into());
-    }
-    if private_key.is_some() && account.is_some() {
-        return Err("Only one of private key or account path can be provided".into());
-    }
-
-    // Parse contract address
-    let contract_address = Address::from_slice(&hex::decode(contract_address.replace("0x", ""))?);
-
-    // Set up the provider and wallet
-    let eth_signer = if let Some(private_key) = private_key {
-        private_key.parse().expect("Failed to parse private key")
-    } else if let Some(account) = account {
-        if !std::fs::exists(&account)? {
-            return Err("Account file does not exist".into());
+    options: BuyOptions,
+    passphrase: Passphrase,
+) -> Result<QuoteReport, CliError> {
+    let mut client = StealthGasClient::new(ClientConfig {
+        chain_id,
+        rpc_url,
+        gas_station_address: contract_address,
+        passphrase,
+        ..Default::default()
+    })?;
+
+    // Simulate from the buyer's address, which needs no key when given with --from
+    let from =
+        match from {
+            Some(from) => parse_address(&from)?,
+            None if signer.is_set() => {
+                NetworkWallet::<Ethereum>::default_signer_address(&signer.wallet().await?)
+            }
+            None => return Err(CliError::InvalidInput(
+                "Pass --from <address> (or a signer option such as --key) to simulate the purchase"
+                    .to_string(),
+            )),
         };
-        let password = rpassword::prompt_password("Enter keystore password:")?;
-        PrivateKeySigner::decrypt_keystore(account, password).expect("failed to unlock keystore")
-    } else {
-        return Err("Neither private key or keystore provided".into());
+
+    // Quote exactly the tickets `buy` would submit; the input files are imported in memory only
+    let batches = client.select_purchase(&inputs, &batches, all)?;
+    client.quote_purchase(from, &batches, &options).await
+}
+
+/// Sign an unsigned purchase with the wallet, without any network access
+pub async fn sign_purchase(
+    unsigned: &UnsignedPurchase,
+    wallet: &EthereumWallet,
+) -> Result<SignedPurchase, CliError> {
+    let signer = NetworkWallet::<Ethereum>::default_signer_address(wallet);
+    if signer != unsigned.from {
+        return Err(CliError::InvalidInput(format!(
+            "The transaction was built for {}, but the signing account is {}",
+            unsigned.from, signer
+        )));
+    }
+    let envelope = TransactionRequest::default()
+        .with_chain_id(unsigned.chain_id)
+        .with_from(unsigned.from)
+        .with_to(unsigned.to)
+        .with_value(unsigned.value)
+        .with_nonce(unsigned.nonce)
+        .with_gas_limit(unsigned.gas_limit)
+        .with_max_fee_per_gas(unsigned.max_fee_per_gas)
+        .with_max_priority_fee_per_gas(unsigned.max_priority_fee_per_gas)
+        .with_input(unsigned.input.clone())
+        .build(wallet)
+        .await
+        .map_err(|e| CliError::InvalidInput(format!("Failed to sign the transaction: {}", e)))?;
+    Ok(SignedPurchase {
+        chain_id: unsigned.chain_id,
+        batch_id: unsigned.batch_id.clone(),
+        ticket_ids: unsigned.ticket_ids.clone(),
+        from: unsigned.from,
+        tx_hash: *envelope.tx_hash(),
+        raw: envelope.encoded_2718().into(),
+    })
+}
+
+/// `buy build`: write the unsigned purchase transaction to a file for offline signing
+#[allow(clippy::too_many_arguments)]
+pub async fn build(
+    rpc_url: Option<String>,
+    contract_address: Option<String>,
+    input: Option<String>,
+    batch: Option<String>,
+    from: String,
+    chain_id: Option<u64>,
+    output: String,
+    options: BuyOptions,
+    passphrase: Passphrase,
+) -> Result<BuildReport, CliError> {
+    ensure_new_file(&output)?;
+    let mut client = StealthGasClient::new(ClientConfig {
+        chain_id,
+        rpc_url,
+        gas_station_address: contract_address,
+        passphrase,
+        ..Default::default()
+    })?;
+    let from = parse_address(&from)?;
+
+    // Broadcasting later updates the batch, so tickets from an input file are imported
+    let batch = match input {
+        Some(input_path) => Some(client.import_file(&input_path)?),
+        None => batch,
     };
-    let signer_provider = ProviderBuilder::new()
-        .with_recommended_fillers()
-        .wallet(EthereumWallet::from(eth_signer))
-        .on_http(rpc_url.parse()?);
 
-    // Create contract instance
-    let contract = IStealthGasStation::new(contract_address, signer_provider.clone());
+    let unsigned = client
+        .build_purchase(from, batch.as_deref(), &options)
+        .await?;
+    fs::write(&output, serde_json::to_string_pretty(&unsigned)?)?;
 
-    // Load unsigned tickets
-    let unsigned_tickets: Vec<UnsignedTicket> = serde_json::from_str(&fs::read_to_string(&input_path)?)?;
+    let gas_fee = U256::from(unsigned.gas_limit) * U256::from(unsigned.max_fee_per_gas);
+    Ok(BuildReport {
+        batch_id: unsigned.batch_id,
+        chain_id: unsigned.chain_id,
+        tickets: unsigned.ticket_ids.len(),
+        from: unsigned.from,
+        to: unsigned.to,
+        nonce: unsigned.nonce,
+        value: unsigned.value,
+        gas_limit: unsigned.gas_limit,
+        max_fee_per_gas: unsigned.max_fee_per_gas,
+        max_priority_fee_per_gas: unsigned.max_priority_fee_per_gas,
+        total_cost: unsigned.value + gas_fee,
+        output,
+    })
+}
 
-    // Get costs from contract
-    let ticket_cost = contract.ticketCost().call().await?._0;
-    let shipping_cost = contract.shippingCost().call().await?._0;
+/// `buy calldata`: print the purchase call for a Safe or another smart contract wallet to
+/// propose, optionally writing it as a Safe Transaction Builder batch
+#[allow(clippy::too_many_arguments)]
+pub async fn calldata(
+    rpc_url: Option<String>,
+    contract_address: Option<String>,
+    input: Option<String>,
+    batch: Option<String>,
+    safe: Option<String>,
+    chain_id: Option<u64>,
+    safe_json: Option<String>,
+    passphrase: Passphrase,
+) -> Result<CalldataReport, CliError> {
+    if let Some(path) = &safe_json {
+        ensure_new_file(path)?;
+    }
+    let mut client = StealthGasClient::new(ClientConfig {
+        chain_id,
+        rpc_url,
+        gas_station_address: contract_address,
+        passphrase,
+        ..Default::default()
+    })?;
+    let safe = safe.as_deref().map(parse_address).transpose()?;
 
-    // Calculate total cost
-    let total_cost = ticket_cost * U256::from(unsigned_tickets.len()) + shipping_cost;
+    // The tickets are tracked as pending, so tickets from an input file are imported
+    let batch = match input {
+        Some(input_path) => Some(client.import_file(&input_path)?),
+        None => batch,
+    };
 
-    // Prepare blinded messages for contract call
-    let blinded_messages: Vec<Bytes> = unsigned_tickets
-        .iter()
-        .map(|t| Bytes::from(t.blind_msg.clone()))
-        .collect();
+    let mut report = client.export_purchase(batch.as_deref(), safe).await?;
+    if let Some(path) = safe_json {
+        fs::write(
+            &path,
+            serde_json::to_string_pretty(&safe_batch(&report, safe))?,
+        )?;
+        report.safe_json = Some(path);
+    }
+    Ok(report)
+}
 
-    // Create and send transaction
-    let tx = contract
-        .buyGasTickets(blinded_messages)
-        .value(total_cost)
-        .send()
-        .await?;
+/// `buy sign`: sign a transaction written by `buy build`; needs no RPC or ticket wallet
+pub async fn sign(
+    input: String,
+    output: String,
+    signer: SignerOptions,
+) -> Result<SignReport, CliError> {
+    ensure_new_file(&output)?;
+    let unsigned: UnsignedPurchase = parse_json_file(&input, read_input_file(&input)?.as_bytes())?;
+    let wallet = signer.wallet().await?;
 
-    println!("Transaction sent! Hash: {}", tx.tx_hash());
+    let signed = sign_purchase(&unsigned, &wallet).await?;
+    fs::write(&output, serde_json::to_string_pretty(&signed)?)?;
 
-    Ok(())
+    Ok(SignReport {
+        batch_id: signed.batch_id,
+        chain_id: signed.chain_id,
+        tickets: signed.ticket_ids.len(),
+        from: signed.from,
+        to: unsigned.to,
+        nonce: unsigned.nonce,
+        value: unsigned.value,
+        tx_hash: signed.tx_hash,
+        output,
+    })
+}
+
+/// `buy release`: make the tickets of an abandoned `buy calldata` proposal purchasable again
+pub fn release(
+    batch: Option<String>,
+    chain_id: Option<u64>,
+    passphrase: Passphrase,
+) -> Result<ReleaseReport, CliError> {
+    let mut client = StealthGasClient::new(ClientConfig {
+        chain_id,
+        passphrase,
+        ..Default::default()
+    })?;
+    client.release_purchase(batch.as_deref())
+}
+
+/// Read a transaction signed with `buy sign`, with the chain it was signed for. A chain given
+/// with --chain-id must match it
+pub fn read_signed_purchase(
+    input: &str,
+    chain_id: Option<u64>,
+) -> Result<(SignedPurchase, u64), CliError> {
+    let signed: SignedPurchase = parse_json_file(input, read_input_file(input)?.as_bytes())?;
+    let signed_chain_id = signed.signed_chain_id()?;
+    if let Some(chain_id) = chain_id.filter(|c| *c != signed_chain_id) {
+        return Err(CliError::InvalidInput(format!(
+            "The transaction in {} is signed for chain {}, not --chain-id {}",
+            input, signed_chain_id, chain_id
+        )));
+    }
+    Ok((signed, signed_chain_id))
+}
+
+/// `buy broadcast`: submit a transaction signed with `buy sign` and wait for it
+pub async fn broadcast(
+    rpc_url: Option<String>,
+    contract_address: Option<String>,
+    signed: SignedPurchase,
+    chain_id: u64,
+    options: BuyOptions,
+    passphrase: Passphrase,
+) -> Result<BuyReport, CliError> {
+    let mut client = StealthGasClient::new(ClientConfig {
+        chain_id: Some(chain_id),
+        rpc_url,
+        gas_station_address: contract_address,
+        passphrase,
+        progress: stderr_progress(),
+        ..Default::default()
+    })?;
+
+    client.broadcast_purchase(&signed, &options).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::{temp_wallet, ticket, PUBKEY};
+    use crate::store::TicketStore;
+    use alloy::primitives::FixedBytes;
+    use alloy::signers::local::PrivateKeySigner;
+    use std::path::Path;
+
+    const GAS_STATION: &str = "0x943285f1a29281e59514fF35Dc16E5a14E123a27";
+
+    /// Client on chain 1 for the test coordinator, over a wallet holding one batch per entry
+    fn client(path: &Path, batches: &[&[u8]]) -> (StealthGasClient, Vec<String>) {
+        let mut store = TicketStore::open_at(path, &Passphrase::default()).unwrap();
+        let ids = batches
+            .iter()
+            .map(|tickets| {
+                store
+                    .add_batch(1, PUBKEY, tickets.iter().map(|n| ticket(*n)).collect())
+                    .unwrap()
+            })
+            .collect();
+        store.save().unwrap();
+        let config = ClientConfig {
+            chain_id: Some(1),
+            // Nothing listens here: tests never get as far as sending a request
+            rpc_url: Some("http://127.0.0.1:1".to_string()),
+            gas_station_address: Some(GAS_STATION.to_string()),
+            coordinator_pubkey: Some(PUBKEY.to_string()),
+            ..Default::default()
+        };
+        (StealthGasClient::with_store(config, store), ids)
+    }
+
+    fn input_file(path: &Path, name: &str, tickets: &[u8]) -> String {
+        let tickets: Vec<UnsignedTicket> = tickets.iter().map(|n| ticket(*n)).collect();
+        let file = path.with_file_name(name);
+        fs::write(&file, serde_json::to_string(&tickets).unwrap()).unwrap();
+        file.display().to_string()
+    }
+
+    fn ids(batches: &[(String, Vec<UnsignedTicket>)]) -> Vec<(String, Vec<FixedBytes<32>>)> {
+        batches
+            .iter()
+            .map(|(id, tickets)| (id.clone(), tickets.iter().map(|t| t.id).collect()))
+            .collect()
+    }
+
+    fn ticket_ids(batches: &[(String, Vec<UnsignedTicket>)]) -> Vec<FixedBytes<32>> {
+        batches
+            .iter()
+            .flat_map(|(_, tickets)| tickets.iter().map(|t| t.id))
+            .collect()
+    }
+
+    #[test]
+    fn dry_run_quotes_the_tickets_buy_submits() {
+        let path = temp_wallet();
+        let (mut dry_run, batches) = client(&path, &[&[1, 2, 3]]);
+        dry_run
+            .store
+            .batch_mut(&batches[0])
+            .unwrap()
+            .ticket_mut(&ticket(1).id)
+            .unwrap()
+            .transition(TicketState::PurchaseTxPending, None, None)
+            .unwrap();
+        dry_run.store.save().unwrap();
+        let inputs = [
+            input_file(&path, "bought.json", &[1, 2, 3]),
+            input_file(&path, "new.json", &[4]),
+        ];
+
+        // `buy --dry-run` and `buy` each select from the wallet as found on disk
+        let quoted = dry_run.select_purchase(&inputs, &[], false).unwrap();
+        dry_run.validate_purchase(&quoted).unwrap();
+        let (mut buy, _) = client(&path, &[]);
+        assert_eq!(buy.store().batches.len(), 1, "the dry run saved its import");
+        let submitted = buy.select_purchase(&inputs, &[], false).unwrap();
+        buy.validate_purchase(&submitted).unwrap();
+
+        let expected = vec![ticket(2).id, ticket(3).id, ticket(4).id];
+        assert_eq!(ticket_ids(&quoted), expected);
+        assert_eq!(ticket_ids(&submitted), expected);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn batches_for_different_coordinators_are_not_bought_together() {
+        let path = temp_wallet();
+        let (mut client, mut batches) = client(&path, &[&[1]]);
+        batches.push(
+            client
+                .store
+                .add_batch(1, "0x1234", vec![ticket(2)])
+                .unwrap(),
+        );
+
+        let selected = client.purchasable_batches(&batches).unwrap();
+        let error = client.validate_purchase(&selected).unwrap_err();
+        assert!(
+            matches!(&error, CliError::InvalidInput(m) if m.contains("different coordinator keys")),
+            "{}",
+            error
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn tickets_named_twice_are_bought_once() {
+        let path = temp_wallet();
+        let (mut client, batches) = client(&path, &[&[1, 2, 3, 4], &[5]]);
+        let first = input_file(&path, "first.json", &[1, 2]);
+        let second = input_file(&path, "second.json", &[2, 3]);
+
+        let selected = client
+            .select_purchase(&[first.clone(), second], &[], false)
+            .unwrap();
+        assert_eq!(
+            ids(&selected),
+            vec![(
+                batches[0].clone(),
+                vec![ticket(1).id, ticket(2).id, ticket(3).id]
+            )]
+        );
+
+        // A file and the batch holding it, and a repeated batch id
+        let selected = client
+            .select_purchase(
+                &[first],
+                &[batches[0].clone(), batches[1].clone(), batches[1].clone()],
+                false,
+            )
+            .unwrap();
+        assert_eq!(
+            ids(&selected),
+            vec![
+                (
+                    batches[0].clone(),
+                    vec![ticket(1).id, ticket(2).id, ticket(3).id, ticket(4).id]
+                ),
+                (batches[1].clone(), vec![ticket(5).id]),
+            ]
+        );
+        client.validate_purchase(&selected).unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn input_file_buys_only_its_own_tickets() {
+        let path = temp_wallet();
+        let (mut client, batches) = client(&path, &[&[1, 2, 3]]);
+        let subset = input_file(&path, "subset.json", &[1, 2]);
+
+        let selected = client.select_purchase(&[subset], &[], false).unwrap();
+        assert_eq!(
+            ids(&selected),
+            vec![(batches[0].clone(), vec![ticket(1).id, ticket(2).id])]
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    /// Purchase of the tickets signed offline like `buy sign`, after `tamper` edits it
+    async fn signed_purchase(
+        batch_id: &str,
+        tickets: &[u8],
+        tamper: impl FnOnce(&mut UnsignedPurchase),
+    ) -> SignedPurchase {
+        let key = PrivateKeySigner::random();
+        let tickets: Vec<UnsignedTicket> = tickets.iter().map(|n| ticket(*n)).collect();
+        let mut unsigned = UnsignedPurchase {
+            chain_id: 1,
+            batch_id: batch_id.to_string(),
+            ticket_ids: tickets.iter().map(|t| t.id).collect(),
+            from: key.address(),
+            to: parse_address(GAS_STATION).unwrap(),
+            value: U256::from(1),
+            nonce: 0,
+            gas_limit: 100_000,
+            max_fee_per_gas: 2_000_000_000,
+            max_priority_fee_per_gas: 1_000_000_000,
+            input: buy_calldata(&tickets),
+        };
+        tamper(&mut unsigned);
+        sign_purchase(&unsigned, &EthereumWallet::from(key))
+            .await
+            .unwrap()
+    }
+
+    /// Broadcast `signed`, expecting it to be rejected before anything is sent or recorded
+    async fn rejected_broadcast(client: &mut StealthGasClient, signed: &SignedPurchase) -> String {
+        let Err(error) = client
+            .broadcast_purchase(signed, &BuyOptions::default())
+            .await
+        else {
+            panic!("the transaction was broadcast");
+        };
+        let tickets = &client.store().batch(&signed.batch_id).unwrap().tickets;
+        assert!(tickets.iter().all(|t| t.state.is_purchasable()));
+        match error {
+            CliError::InvalidInput(message) => message,
+            error => panic!("unexpected error: {}", error),
+        }
+    }
+
+    #[tokio::test]
+    async fn broadcast_rejects_a_transaction_to_another_contract() {
+        let path = temp_wallet();
+        let (mut client, batches) = client(&path, &[&[1, 2]]);
+        let signed = signed_purchase(&batches[0], &[1, 2], |tx| {
+            tx.to = Address::repeat_byte(0x11)
+        })
+        .await;
+
+        let message = rejected_broadcast(&mut client, &signed).await;
+        assert!(
+            message.contains("not the StealthGasStation contract"),
+            "{}",
+            message
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn broadcast_rejects_calldata_for_other_tickets() {
+        let path = temp_wallet();
+        let (mut client, batches) = client(&path, &[&[1, 2]]);
+        let signed = signed_purchase(&batches[0], &[1, 2], |tx| {
+            tx.input = buy_calldata(&[ticket(1), ticket(3)])
+        })
+        .await;
+
+        let message = rejected_broadcast(&mut client, &signed).await;
+        assert!(
+            message.contains("does not buy the 2 tickets"),
+            "{}",
+            message
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn broadcast_checks_the_chain_and_sender_of_the_envelope() {
+        let path = temp_wallet();
+        let (mut client, batches) = client(&path, &[&[1, 2]]);
+
+        // The file's chain_id is not signed: editing it does not change the envelope's chain
+        let mut signed = signed_purchase(&batches[0], &[1, 2], |tx| tx.chain_id = 5).await;
+        signed.chain_id = 1;
+        let message = rejected_broadcast(&mut client, &signed).await;
+        assert!(
+            message.contains("signed for chain 5, not chain 1"),
+            "{}",
+            message
+        );
+
+        let mut signed = signed_purchase(&batches[0], &[1, 2], |_| ()).await;
+        signed.from = Address::repeat_byte(0x22);
+        let message = rejected_broadcast(&mut client, &signed).await;
+        assert!(
+            message.contains("The transaction is signed by"),
+            "{}",
+            message
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn release_defaults_to_the_most_recent_exported_batch() {
+        let path = temp_wallet();
+        let (mut client, batches) = client(&path, &[&[1], &[2]]);
+        // The newer batch was bought with an ordinary transaction, the older one exported
+        for (batch, n, tx_hash) in [
+            (&batches[0], 1, None),
+            (&batches[1], 2, Some(TxHash::repeat_byte(1))),
+        ] {
+            client
+                .store
+                .batch_mut(batch)
+                .unwrap()
+                .ticket_mut(&ticket(n).id)
+                .unwrap()
+                .transition(TicketState::PurchaseTxPending, tx_hash, None)
+                .unwrap();
+        }
+
+        let report = client.release_purchase(None).unwrap();
+        assert_eq!(report.batch_id, batches[0]);
+        assert_eq!(report.tickets, 1);
+        let ticket = &client.store().batch(&batches[1]).unwrap().tickets[0];
+        assert_eq!(ticket.state, TicketState::PurchaseTxPending);
+        assert!(client.release_purchase(None).is_err());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
 }
@@ -0,0 +1,111 @@
+use crate::config::Config;
+use crate::error::CliError;
+use serde::Serialize;
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Serialize)]
+pub struct ConfigReport {
+    pub path: PathBuf,
+    pub profile: Option<String>,
+    /// The whole config file, or only the selected profile
+    pub settings: toml::Table,
+}
+
+impl fmt::Display for ConfigReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Config file: {}", self.path.display())?;
+        if let Some(name) = &self.profile {
+            writeln!(f, "[profiles.{}]", name)?;
+        }
+        write!(
+            f,
+            "{}",
+            toml::to_string_pretty(&self.settings).map_err(|_| fmt::Error)?
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct ConfigChange {
+    pub key: String,
+    /// New value, or None if the key was removed
+    pub value: Option<String>,
+    pub scope: String,
+}
+
+impl fmt::Display for ConfigChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            Some(value) => writeln!(f, "Set {} = {} in {}", self.key, value, self.scope),
+            None => writeln!(f, "Unset {} in {}", self.key, self.scope),
+        }
+    }
+}
+
+pub fn show(profile: Option<String>) -> Result<ConfigReport, CliError> {
+    let config = Config::load()?;
+
+    let settings = match &profile {
+        Some(name) => toml::Table::try_from(config.profile(Some(name))?)?,
+        None => toml::Table::try_from(&config)?,
+    };
+
+    Ok(ConfigReport {
+        path: Config::path()?,
+        profile,
+        settings,
+    })
+}
+
+pub fn set(profile: Option<String>, key: String, value: String) -> Result<ConfigChange, CliError> {
+    let mut config = Config::load()?;
+
+    let scope = if key == "default_profile" {
+        config.default_profile = Some(value.clone());
+        "config".to_string()
+    } else {
+        let name = config.profile_name(profile.as_deref()).ok_or_else(|| {
+            CliError::InvalidInput("Select the profile to edit with --profile <name>".to_string())
+        })?;
+        config
+            .profiles
+            .entry(name.clone())
+            .or_default()
+            .set(&key, value.clone())?;
+        format!("profile {}", name)
+    };
+    config.save()?;
+
+    Ok(ConfigChange {
+        key,
+        value: Some(value),
+        scope,
+    })
+}
+
+pub fn unset(profile: Option<String>, key: String) -> Result<ConfigChange, CliError> {
+    let mut config = Config::load()?;
+
+    let scope = if key == "default_profile" {
+        config.default_profile = None;
+        "config".to_string()
+    } else {
+        let name = config.profile_name(profile.as_deref()).ok_or_else(|| {
+            CliError::InvalidInput("Select the profile to edit with --profile <name>".to_string())
+        })?;
+        let selected = config
+            .profiles
+            .get_mut(&name)
+            .ok_or_else(|| CliError::Config(format!("Profile {} not found", name)))?;
+        selected.unset(&key)?;
+        format!("profile {}", name)
+    };
+    config.save()?;
+
+    Ok(ConfigChange {
+        key,
+        value: None,
+        scope,
+    })
+}
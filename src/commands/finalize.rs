@@ -1,18 +1,24 @@
-use crate::commands::utils::{get_default_contract_address, get_default_pubkey, get_default_rpc};
+use crate::client::{ClientConfig, StealthGasClient};
+use crate::commands::tx::purchase_outcome;
+use crate::encryption::Passphrase;
+use crate::error::CliError;
+use crate::output::stderr_progress;
+use crate::store::{TicketState, TicketStore};
 use alloy::{
-    hex,
-    primitives::{Address, FixedBytes},
-    providers::{Provider, ProviderBuilder},
+    primitives::{Address, FixedBytes, TxHash},
+    providers::Provider,
     rpc::types::Filter,
     sol,
     sol_types::SolEvent,
+    transports::Transport,
 };
-use dirs;
-use eth_stealth_gas_tickets::{BlindedSignature, TicketsVerifier, UnsignedTicket};
-use serde::{Deserialize, Serialize};
-use std::fs;
-use std::io::Write;
+use eth_stealth_gas_tickets::{BlindedSignature, SignedTicket, TicketsVerifier};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
 use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
 
 sol! {
     #[sol(rpc)]
@@ -22,144 +28,508 @@ sol! {
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct FinalizeInput {
-    unsigned_tickets: Vec<UnsignedTicket>,
-    blind_signatures: Vec<BlindedSignature>,
+// Maximum block range per eth_getLogs request
+const SCAN_WINDOW: u64 = 50_000;
+
+#[derive(Serialize)]
+pub struct FinalizeReport {
+    pub batch_id: String,
+    pub chain_id: u64,
+    /// Blind signatures this run found for the batch's tickets
+    pub signatures_found: usize,
+    pub finalized: usize,
+    pub finalized_total: usize,
+    pub tickets: usize,
+    pub missing: Vec<FixedBytes<32>>,
+    pub failed: Vec<FinalizeFailure>,
+    pub output: Option<String>,
+    pub exported: Option<usize>,
+}
+
+/// A ticket whose blind signature could not be finalized; it keeps the signature for a retry
+#[derive(Serialize)]
+pub struct FinalizeFailure {
+    pub id: FixedBytes<32>,
+    pub error: String,
 }
 
+impl fmt::Display for FinalizeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Scan Finished. Found {} matching tickets.",
+            self.signatures_found
+        )?;
+        if self.finalized > 0 {
+            writeln!(
+                f,
+                "Finalized {} tickets in batch {}",
+                self.finalized, self.batch_id
+            )?;
+        }
+        for failure in &self.failed {
+            writeln!(
+                f,
+                "Failed to finalize ticket {}: {}",
+                failure.id, failure.error
+            )?;
+        }
+        writeln!(
+            f,
+            "Batch {}: {} of {} tickets finalized",
+            self.batch_id, self.finalized_total, self.tickets
+        )?;
+        if !self.missing.is_empty() {
+            writeln!(
+                f,
+                "{} purchased tickets still missing a blind signature (re-run finalize later):",
+                self.missing.len()
+            )?;
+            for id in &self.missing {
+                writeln!(f, "  {}", id)?;
+            }
+        }
+        if let (Some(output), Some(exported)) = (&self.output, self.exported) {
+            writeln!(f, "Exported {} new signed tickets to {}", exported, output)?;
+        }
+        Ok(())
+    }
+}
+
+/// Polling settings for `finalize` in watch mode
+#[derive(Clone, Copy)]
+pub struct WatchOptions {
+    pub poll_interval: Duration,
+    pub timeout: Duration,
+}
+
+impl StealthGasClient {
+    /// Scan for the coordinator's blind signatures and finalize the tickets of a stored batch
+    /// (the most recent bought one awaiting signatures if none is given). With `watch`, keep polling
+    /// until every purchased ticket is signed or the timeout expires
+    pub async fn finalize(
+        &mut self,
+        batch_id: Option<&str>,
+        start_block: Option<u64>,
+        watch: Option<WatchOptions>,
+    ) -> Result<FinalizeReport, CliError> {
+        let chain_id = self.chain_id();
+        let pubkey_hex = self.coordinator_pubkey()?;
+        let verifier = self.verifier()?;
+        let batch_id = self.resolve_batch(batch_id, Some(&pubkey_hex), |s| {
+            s.is_awaiting_finalization()
+        })?;
+        let provider = self.provider()?;
+        let contract_address = self.gas_station_address()?;
+
+        resolve_pending_purchases(&provider, &mut self.store, &batch_id).await?;
+
+        // Resume from the last scanned block, or start at the block the tickets were bought in,
+        // unless a start block is given explicitly
+        let start_block = match start_block
+            .or(self.store.checkpoint(chain_id, &contract_address).map(|b| b + 1))
+            .or(self.store.earliest_purchase_block(chain_id))
+        {
+            Some(start_block) => start_block,
+            None => {
+                return Err(CliError::InvalidInput(
+                    "Unknown purchase block for these tickets, pass --start-block (the block of your buy tx)"
+                        .to_string(),
+                ))
+            }
+        };
+
+        // Get current block number
+        let head = provider.get_block_number().await?;
+        let mut found = 0;
+
+        if self.store.awaiting_signature_ids(chain_id).is_empty() {
+            self.progress("No tickets awaiting signatures, skipping scan");
+        } else if start_block > head {
+            self.progress(&format!("Already scanned up to block {}", head));
+        } else {
+            self.progress(&format!(
+                "Scanning for events from block {} to {}",
+                start_block, head
+            ));
+            found = scan_signatures(
+                &provider,
+                &mut self.store,
+                chain_id,
+                &batch_id,
+                contract_address,
+                start_block,
+                head,
+            )
+            .await?;
+        }
+
+        let (mut finalized, mut failed) = finalize_received(&mut self.store, &batch_id, &verifier)?;
+
+        // In watch mode keep polling for new SendGasTickets events until every bought ticket is signed
+        if let Some(watch) = watch {
+            let deadline = Instant::now() + watch.timeout;
+            loop {
+                let waiting = self
+                    .store
+                    .batch(&batch_id)?
+                    .count(|s| s.is_awaiting_coordinator());
+                if waiting == 0 {
+                    self.progress(&format!(
+                        "All purchased tickets in batch {} are signed",
+                        batch_id
+                    ));
+                    break;
+                }
+                if Instant::now() >= deadline {
+                    return Err(CliError::Timeout(format!(
+                        "Timed out after {}s with {} tickets in batch {} still awaiting signatures",
+                        watch.timeout.as_secs(),
+                        waiting,
+                        batch_id
+                    )));
+                }
+
+                self.progress(&format!(
+                    "Waiting for {} blind signatures (polling every {}s)...",
+                    waiting,
+                    watch.poll_interval.as_secs()
+                ));
+                sleep(watch.poll_interval).await;
+
+                resolve_pending_purchases(&provider, &mut self.store, &batch_id).await?;
+                let head = provider.get_block_number().await?;
+                let from_block = self
+                    .store
+                    .checkpoint(chain_id, &contract_address)
+                    .map_or(start_block, |b| b + 1);
+                if from_block <= head {
+                    found += scan_signatures(
+                        &provider,
+                        &mut self.store,
+                        chain_id,
+                        &batch_id,
+                        contract_address,
+                        from_block,
+                        head,
+                    )
+                    .await?;
+                }
+                // Every poll retries all received signatures, so its failures replace the last ones
+                let (newly_finalized, still_failed) =
+                    finalize_received(&mut self.store, &batch_id, &verifier)?;
+                finalized += newly_finalized;
+                failed = still_failed;
+            }
+        }
+
+        report(&self.store, &batch_id, chain_id, found, finalized, failed)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn run(
     pubkey: Option<String>,
     input: Option<String>,
+    batch: Option<String>,
     output: Option<String>,
     rpc: Option<String>,
     contract_address: Option<String>,
-    start_block: u64,
+    start_block: Option<u64>,
     chain_id: Option<u64>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // Get chain ID and defaults
-    let chain_id = chain_id.unwrap_or(17000);
-
-    // Use provided values or defaults
-    let rpc_url = rpc.unwrap_or(get_default_rpc(chain_id));
-    let contract = contract_address.unwrap_or(get_default_contract_address(chain_id));
-    let pubkey_hex = pubkey.unwrap_or(get_default_pubkey(chain_id));
-    let mut input_path = input.unwrap_or_else(|| "".to_string());
-    let mut output_path = output.unwrap_or_else(|| "".to_string());
-
-    // If input path is empty, use default path in ~/.stealthereum
-    if input_path.is_empty() {
-        let home_dir = dirs::home_dir().expect("Could not find home directory");
-        let stealth_dir = home_dir.join(".stealthereum");
-        input_path = stealth_dir
-            .join(format!("unsigned_tickets_{}.json", chain_id))
-            .to_str()
-            .expect("Failed to convert path to string")
-            .to_string();
-    }
+    watch: bool,
+    poll_interval: u64,
+    timeout: u64,
+    passphrase: Passphrase,
+) -> Result<FinalizeReport, CliError> {
+    let mut client = StealthGasClient::new(ClientConfig {
+        chain_id,
+        rpc_url: rpc,
+        gas_station_address: contract_address,
+        coordinator_pubkey: pubkey,
+        passphrase,
+        progress: stderr_progress(),
+        ..Default::default()
+    })?;
 
-    // If output path is empty, use default path in ~/.stealthereum
-    if output_path.is_empty() {
-        let home_dir = dirs::home_dir().expect("Could not find home directory");
-        let stealth_dir = home_dir.join(".stealthereum");
-        if !stealth_dir.exists() {
-            std::fs::create_dir_all(&stealth_dir)
-                .expect("Failed to create .stealthereum directory");
+    // Load the unsigned tickets from the input file (importing it into the wallet) or from a stored batch
+    let batch_id = match input {
+        Some(input_path) => client.import_file(&input_path)?,
+        None => {
+            let pubkey_hex = client.coordinator_pubkey()?;
+            client.resolve_batch(batch.as_deref(), Some(&pubkey_hex), |s| {
+                s.is_awaiting_finalization()
+            })?
         }
-        output_path = stealth_dir
-            .join(format!("finalized_tickets_{}.json", chain_id))
-            .to_str()
-            .expect("Failed to convert path to string")
-            .to_string();
-    }
+    };
 
-    // Load the unsigned tickets from the file
-    let all_unsigned_tickets: Vec<UnsignedTicket> =
-        serde_json::from_str(&fs::read_to_string(&input_path)?)?;
-    let ticket_ids: Vec<FixedBytes<32>> = all_unsigned_tickets.iter().map(|t| t.id).collect();
+    let watch = watch.then_some(WatchOptions {
+        poll_interval: Duration::from_secs(poll_interval),
+        timeout: Duration::from_secs(timeout),
+    });
+    let result = client.finalize(Some(&batch_id), start_block, watch).await;
 
-    // Set up the provider
-    let provider = ProviderBuilder::new().on_http(rpc_url.parse().unwrap());
+    // Export the finalized tickets, even when giving up on the missing signatures
+    let exported = match &output {
+        Some(output_path) => Some(merge_signed_tickets_file(
+            client.store(),
+            output_path,
+            client
+                .store()
+                .batch(&batch_id)?
+                .signed_tickets(|s| s.is_finalized()),
+        )?),
+        None => None,
+    };
 
-    let contract_address = Address::from_slice(&hex::decode(contract.replace("0x", "")).unwrap());
+    let mut report = result?;
+    report.output = output;
+    report.exported = exported;
+    Ok(report)
+}
 
-    // Get current block number
-    let mut current_end_block = provider.get_block_number().await?;
-    let mut blind_signatures = Vec::new();
-    let mut unsigned_tickets = Vec::new();
+/// Summarize the batch and list the purchased tickets still missing a blind signature
+fn report(
+    store: &TicketStore,
+    batch_id: &str,
+    chain_id: u64,
+    signatures_found: usize,
+    finalized: usize,
+    failed: Vec<FinalizeFailure>,
+) -> Result<FinalizeReport, CliError> {
+    let batch = store.batch(batch_id)?;
 
-    println!("Scanning for events from block {}", start_block);
+    let missing: Vec<FixedBytes<32>> = batch
+        .tickets
+        .iter()
+        .filter(|t| t.state.is_awaiting_coordinator())
+        .map(|t| t.unsigned.id)
+        .collect();
 
-    while start_block < current_end_block {
-        let current_start_block = if current_end_block >= 49999 {
-            current_end_block - 49999
-        } else {
-            0
+    Ok(FinalizeReport {
+        batch_id: batch_id.to_string(),
+        chain_id,
+        signatures_found,
+        finalized,
+        finalized_total: batch.count(|s| s.is_finalized()),
+        tickets: batch.tickets.len(),
+        missing,
+        failed,
+        output: None,
+        exported: None,
+    })
+}
+
+/// Merge signed tickets into a Vec<SignedTicket> JSON file, skipping tickets it already holds
+/// (the file is encrypted like the wallet)
+fn merge_signed_tickets_file(
+    store: &TicketStore,
+    path: &str,
+    signed_tickets: Vec<SignedTicket>,
+) -> Result<usize, CliError> {
+    let mut merged: Vec<SignedTicket> = if Path::new(path).exists() {
+        store.read_json_file(path)?
+    } else {
+        Vec::new()
+    };
+    let before = merged.len();
+    for ticket in signed_tickets {
+        if !merged.iter().any(|t| t.msg == ticket.msg) {
+            merged.push(ticket);
+        }
+    }
+    store.write_json_file(path, &merged)?;
+    Ok(merged.len() - before)
+}
+
+/// Resolve pending purchase transactions of a batch into purchased or failed tickets (a mined
+/// `tx cancel` self-transfer counts as a failed purchase). Every transaction sent for a ticket
+/// is checked, since the original may be mined instead of its `tx speedup` replacement
+async fn resolve_pending_purchases<T: Transport + Clone, P: Provider<T>>(
+    provider: &P,
+    store: &mut TicketStore,
+    batch_id: &str,
+) -> Result<(), CliError> {
+    let pending_txs: HashSet<TxHash> = store
+        .batch_mut(batch_id)?
+        .tickets
+        .iter()
+        .flat_map(|t| t.pending_purchase_txs())
+        .collect();
+    for tx_hash in pending_txs {
+        let Some(receipt) = provider.get_transaction_receipt(tx_hash).await? else {
+            continue;
+        };
+        let state = purchase_outcome(&receipt);
+        let batch = store.batch_mut(batch_id)?;
+        for ticket in batch
+            .tickets
+            .iter_mut()
+            .filter(|t| t.pending_purchase_txs().contains(&tx_hash))
+        {
+            ticket.transition(state, Some(tx_hash), receipt.block_number)?;
+        }
+    }
+    store.save()?;
+    Ok(())
+}
+
+/// Finalize every ticket of a batch that holds a blind signature but no finalized signature yet.
+/// Tickets are unblinded one by one so a single bad signature does not hold back the rest;
+/// the ones that fail are returned alongside the number finalized
+fn finalize_received(
+    store: &mut TicketStore,
+    batch_id: &str,
+    pubkey: &TicketsVerifier,
+) -> Result<(usize, Vec<FinalizeFailure>), CliError> {
+    let batch = store.batch_mut(batch_id)?;
+
+    let mut finalized = 0;
+    let mut failed = Vec::new();
+    for ticket in batch
+        .tickets
+        .iter_mut()
+        .filter(|t| t.state == TicketState::BlindSignatureReceived)
+    {
+        let Some(blind_signature) = ticket.blind_signature.clone() else {
+            continue;
         };
+        match pubkey.finalize_tickets(vec![ticket.unsigned.clone()], vec![blind_signature]) {
+            Ok(mut signed) => {
+                ticket.signed = signed.pop();
+                ticket.transition(TicketState::Finalized, None, None)?;
+                finalized += 1;
+            }
+            // Keep the blind signature so finalization can be retried (e.g. with the right --pubkey)
+            Err(e) => failed.push(FinalizeFailure {
+                id: ticket.unsigned.id,
+                error: e.to_string(),
+            }),
+        }
+    }
+    store.save()?;
+
+    Ok((finalized, failed))
+}
+
+/// Scan `[from_block, to_block]` for SendGasTickets events in windows of SCAN_WINDOW blocks,
+/// recording every blind signature for a stored ticket and checkpointing after each window
+/// so an interrupted scan resumes where it stopped. Returns how many of the signatures are for
+/// tickets of `batch_id`; those of the chain's other batches are recorded too
+async fn scan_signatures<T: Transport + Clone, P: Provider<T>>(
+    provider: &P,
+    store: &mut TicketStore,
+    chain_id: u64,
+    batch_id: &str,
+    contract_address: Address,
+    from_block: u64,
+    to_block: u64,
+) -> Result<usize, CliError> {
+    let mut found = 0;
+    let mut window_start = from_block;
+
+    while window_start <= to_block {
+        let window_end = to_block.min(window_start.saturating_add(SCAN_WINDOW - 1));
 
         // Set up the event filter for SendGasTickets
         let filter = Filter::new()
             .address(vec![contract_address])
             .event_signature(IStealthGasStation::SendGasTickets::SIGNATURE_HASH)
-            .from_block(current_start_block)
-            .to_block(current_end_block);
+            .from_block(window_start)
+            .to_block(window_end);
 
-        // Fetch and filter events
+        // Fetch and filter events, recording each blind signature against its ticket
         let logs = provider.get_logs(&filter).await?;
         for log in logs {
             if let Ok(decoded) = log.log_decode::<IStealthGasStation::SendGasTickets>() {
-                for (id, signed_data) in decoded.inner.ids.iter().zip(decoded.inner.signed.iter()) {
-                    if ticket_ids.contains(id) {
-                        blind_signatures.push(BlindedSignature {
-                            id: *id,
-                            blind_sig: signed_data.clone(),
-                        });
-                        unsigned_tickets.push(
-                            all_unsigned_tickets
-                                .iter()
-                                .find(|t| t.id == *id)
-                                .unwrap()
-                                .clone(),
-                        );
-                    }
-                }
+                found += record_signatures(
+                    store,
+                    chain_id,
+                    batch_id,
+                    &decoded.inner.data,
+                    log.transaction_hash,
+                    log.block_number,
+                )?;
             }
         }
 
-        current_end_block = current_start_block;
+        store.set_checkpoint(chain_id, &contract_address, window_end);
+        store.save()?;
+        window_start = window_end + 1;
     }
 
-    println!(
-        "Scan Finished. Found {} matching tickets.",
-        blind_signatures.len()
-    );
+    Ok(found)
+}
 
-    if blind_signatures.len() == 0 {
-        println!("No matching tickets found. Exiting.");
-        return Ok(());
+/// Record the blind signatures of one SendGasTickets event against the chain's tickets still
+/// waiting for one, returning how many of them belong to `batch_id`
+fn record_signatures(
+    store: &mut TicketStore,
+    chain_id: u64,
+    batch_id: &str,
+    event: &IStealthGasStation::SendGasTickets,
+    tx_hash: Option<TxHash>,
+    block_number: Option<u64>,
+) -> Result<usize, CliError> {
+    let ticket_ids = store.awaiting_signature_ids(chain_id);
+    let batch = store.batch(batch_id)?;
+    let batch_ids: HashSet<FixedBytes<32>> = batch.tickets.iter().map(|t| t.unsigned.id).collect();
+    let mut found = 0;
+    for (id, signed_data) in event.ids.iter().zip(event.signed.iter()) {
+        if !ticket_ids.contains(id) {
+            continue;
+        }
+        let Some(ticket) = store.ticket_mut(chain_id, id) else {
+            continue;
+        };
+        if ticket.blind_signature.is_some() {
+            continue;
+        }
+        ticket.transition(TicketState::BlindSignatureReceived, tx_hash, block_number)?;
+        ticket.blind_signature = Some(BlindedSignature {
+            id: *id,
+            blind_sig: signed_data.clone(),
+        });
+        if batch_ids.contains(id) {
+            found += 1;
+        }
     }
+    Ok(found)
+}
 
-    // Initialize the ticket verifier
-    let pubkey = TicketsVerifier::from_hex_string(&pubkey_hex).expect("Invalid public key");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::{ticket, PUBKEY};
+    use alloy::primitives::Bytes;
 
-    // Finalize the tickets
-    let signed_tickets = pubkey
-        .finalize_tickets(unsigned_tickets, blind_signatures)
-        .expect("Failed to finalize tickets");
+    #[test]
+    fn only_signatures_for_the_batch_are_counted() {
+        let mut store = TicketStore::default();
+        let batch = store
+            .add_batch(1, PUBKEY, vec![ticket(1), ticket(2)])
+            .unwrap();
+        let other = store.add_batch(1, PUBKEY, vec![ticket(3)]).unwrap();
+        let event = IStealthGasStation::SendGasTickets {
+            ids: vec![ticket(1).id, ticket(3).id, ticket(4).id],
+            signed: vec![Bytes::from(vec![1]); 3],
+        };
 
-    if Path::new(&output_path).exists() {
-        panic!("Output file {} already exists", output_path);
+        let found = record_signatures(&mut store, 1, &batch, &event, None, Some(7)).unwrap();
+        assert_eq!(found, 1);
+        // The other batch's signature is recorded all the same, and only once
+        for (batch_id, n) in [(&batch, 1), (&other, 3)] {
+            let ticket = store
+                .batch_mut(batch_id)
+                .unwrap()
+                .ticket_mut(&ticket(n).id)
+                .unwrap();
+            assert_eq!(ticket.state, TicketState::BlindSignatureReceived);
+            assert!(ticket.blind_signature.is_some());
+        }
+        assert_eq!(
+            record_signatures(&mut store, 1, &other, &event, None, Some(8)).unwrap(),
+            0
+        );
     }
-
-    // Write the signed tickets to the output JSON file
-    let json =
-        serde_json::to_string_pretty(&signed_tickets).expect("Failed to serialize signed tickets");
-    let mut output_file = fs::File::create(&output_path).expect("Failed to create output file");
-    output_file
-        .write_all(json.as_bytes())
-        .expect("Failed to write to file");
-
-    println!("Finalized tickets and saved to {}", output_path);
-
-    Ok(())
 }
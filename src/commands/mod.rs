@@ -1,7 +1,12 @@
+pub mod account;
 pub mod buy;
+pub mod config;
 pub mod finalize;
 pub mod new;
 pub mod params;
 pub mod redeem;
+pub mod status;
+pub mod tx;
 pub mod utils;
 pub mod verify;
+pub mod wallet;
@@ -1,54 +1,93 @@
-use crate::commands::utils::{get_default_pubkey, get_default_tickets_number};
-use dirs;
-use eth_stealth_gas_tickets::TicketsVerifier;
+use crate::client::{ClientConfig, StealthGasClient};
+use crate::commands::utils::{ensure_new_file, parse_pubkey};
+use crate::encryption::Passphrase;
+use crate::error::CliError;
 use rand::thread_rng;
-use std::fs::File;
-use std::io::Write;
-use std::path::Path;
+use serde::Serialize;
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Serialize)]
+pub struct NewReport {
+    pub batch_id: String,
+    pub chain_id: u64,
+    pub tickets: usize,
+    pub wallet: PathBuf,
+    pub output: Option<String>,
+}
+
+impl fmt::Display for NewReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Generated {} tickets in batch {} (stored in {})",
+            self.tickets,
+            self.batch_id,
+            self.wallet.display()
+        )?;
+        if let Some(output) = &self.output {
+            writeln!(f, "Exported unsigned tickets to {}", output)?;
+        }
+        Ok(())
+    }
+}
+
+impl StealthGasClient {
+    /// Generate a batch of unsigned tickets (the chain's default count if none) into the wallet
+    pub fn generate(&mut self, count: Option<usize>) -> Result<NewReport, CliError> {
+        let count = count.map_or_else(|| self.default_tickets(), Ok)?;
+        let pubkey_hex = self.coordinator_pubkey()?;
+
+        let pubkey = parse_pubkey(&pubkey_hex)?;
+        let mut rng = thread_rng();
+
+        let tickets = pubkey.new_blind_tickets(&mut rng, count)?;
+
+        // Record the batch in the local wallet
+        let batch_id = self
+            .store
+            .add_batch(self.chain_id(), &pubkey_hex, tickets)?;
+        self.store.save()?;
+
+        Ok(NewReport {
+            batch_id,
+            chain_id: self.chain_id(),
+            tickets: count,
+            wallet: self.store.path().to_path_buf(),
+            output: None,
+        })
+    }
+}
 
 pub fn run(
     pubkey_hex: Option<String>,
     count: Option<usize>,
     output_path: Option<String>,
     chain_id: Option<u64>,
-) {
-    // Default to chain ID 17000 if not specified
-    let chain_id = chain_id.unwrap_or(17000);
-
-    let pubkey_hex = pubkey_hex.unwrap_or(get_default_pubkey(chain_id));
-    let count = count.unwrap_or(get_default_tickets_number(chain_id));
-    let mut output_path = output_path.unwrap_or_else(|| "".to_string());
-    if output_path.is_empty() {
-        // Create ~/.stealthereum directory if it doesn't exist
-        let home_dir = dirs::home_dir().expect("Could not find home directory");
-        let stealth_dir = home_dir.join(".stealthereum");
-        if !stealth_dir.exists() {
-            std::fs::create_dir_all(&stealth_dir)
-                .expect("Failed to create .stealthereum directory");
-        }
-        output_path = stealth_dir
-            .join(format!("unsigned_tickets_{}.json", chain_id))
-            .to_str()
-            .expect("Failed to convert path to string")
-            .to_string();
+    passphrase: Passphrase,
+) -> Result<NewReport, CliError> {
+    // Refuse to clobber an existing export before generating anything
+    if let Some(output_path) = &output_path {
+        ensure_new_file(output_path)?;
     }
 
-    let pubkey = TicketsVerifier::from_hex_string(&pubkey_hex).expect("Invalid public key");
-    let mut rng = thread_rng();
+    let mut client = StealthGasClient::new(ClientConfig {
+        chain_id,
+        coordinator_pubkey: pubkey_hex,
+        passphrase,
+        ..Default::default()
+    })?;
+    let mut report = client.generate(count)?;
 
-    let tickets = pubkey
-        .new_blind_tickets(&mut rng, count)
-        .expect("Failed to generate tickets");
-
-    // Check if file exists first
-    if Path::new(&output_path).exists() {
-        panic!("Output file {} already exists", output_path);
+    // Optionally export a copy of the batch
+    if let Some(output_path) = output_path {
+        let tickets = client
+            .store()
+            .batch(&report.batch_id)?
+            .unsigned_tickets(|_| true);
+        client.store().write_json_file(&output_path, &tickets)?;
+        report.output = Some(output_path);
     }
 
-    let json = serde_json::to_string_pretty(&tickets).expect("Failed to serialize tickets");
-    let mut file = File::create(&output_path).expect("Failed to create output file");
-    file.write_all(json.as_bytes())
-        .expect("Failed to write to file");
-
-    println!("Generated {} tickets and saved to {}", count, output_path);
+    Ok(report)
 }
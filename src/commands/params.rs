@@ -1,10 +1,14 @@
-use crate::commands::utils::{get_default_contract_address, get_default_rpc, get_default_url};
+use crate::client::{ClientConfig, StealthGasClient};
+use crate::commands::utils::u256_to_eth;
+use crate::error::CliError;
+use crate::output::wei;
 use alloy::{
     hex,
     primitives::{Address, U256},
-    providers::ProviderBuilder,
     sol,
 };
+use serde::Serialize;
+use std::fmt;
 
 sol! {
     #[sol(rpc)]
@@ -15,54 +19,67 @@ sol! {
     }
 }
 
-// Convert U256 (wei) to a human-readable ETH amount as a string
-fn u256_to_eth(wei: U256) -> String {
-    // 10^18 in U256
-    let eth_unit = U256::from(10).pow(U256::from(18));
+#[derive(Serialize)]
+pub struct ParamsReport {
+    pub chain_id: u64,
+    pub gas_station_address: Address,
+    pub coordinator_url: String,
+    #[serde(serialize_with = "wei")]
+    pub ticket_cost: U256,
+    #[serde(serialize_with = "wei")]
+    pub shipping_cost: U256,
+    pub coordinator_pubkey: String,
+}
+
+impl fmt::Display for ParamsReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Chain ID: {}", self.chain_id)?;
+        writeln!(
+            f,
+            "StealthGasStation contract: {}",
+            self.gas_station_address
+        )?;
+        writeln!(f, "Coordinator URL: {}", self.coordinator_url)?;
+        writeln!(f, "Ticket Cost: {}", u256_to_eth(self.ticket_cost))?;
+        writeln!(f, "Shipping Cost: {}", u256_to_eth(self.shipping_cost))?;
+        writeln!(f, "Coordinator PubKey: {}", self.coordinator_pubkey)
+    }
+}
 
-    // Get the whole part and the remainder
-    let whole = wei / eth_unit;
-    let remainder = wei % eth_unit;
+impl StealthGasClient {
+    /// Fetch the public parameters of the StealthGasStation contract
+    pub async fn params(&self) -> Result<ParamsReport, CliError> {
+        let contract_address = self.gas_station_address()?;
+        let contract = IStealthGasStation::new(contract_address, self.provider()?);
 
-    // Convert remainder to a fractional part by scaling it to 18 decimal places
-    let fractional_str = format!("{:018}", remainder);
+        // Fetch the public key and costs from the contract
+        let pubkey_return = contract.coordinatorPubKey().call().await?;
+        let ticket_cost_return = contract.ticketCost().call().await?;
+        let shipping_cost_return = contract.shippingCost().call().await?;
 
-    // Trim trailing zeros from the fractional part
-    format!("{}.{} ETH", whole, fractional_str.trim_end_matches('0'))
+        Ok(ParamsReport {
+            chain_id: self.chain_id(),
+            gas_station_address: contract_address,
+            coordinator_url: self.coordinator_url()?,
+            ticket_cost: ticket_cost_return._0,
+            shipping_cost: shipping_cost_return._0,
+            // Hex-encoded public key, as accepted by --pubkey
+            coordinator_pubkey: format!("0x{}", hex::encode(pubkey_return._0)),
+        })
+    }
 }
 
 pub async fn run(
     rpc_url: Option<String>,
+    contract_address: Option<String>,
     chain_id: Option<u64>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // Use provided chain ID or default to 17000
-    let chain_id = chain_id.unwrap_or(17000);
-
-    // Use provided RPC URL or get default based on chain ID
-    let rpc_url = rpc_url.unwrap_or(get_default_rpc(chain_id));
-
-    // Set up the provider using Arc for shared ownership
-    let provider = ProviderBuilder::new().on_http(rpc_url.parse()?);
-
-    let contract_address_string = get_default_contract_address(chain_id);
-    let contract_address =
-        Address::from_slice(&hex::decode(contract_address_string.replace("0x", "")).unwrap());
-    // Create contract instance with shared provider
-    let contract = IStealthGasStation::new(contract_address, provider.clone());
-
-    // Fetch the public key from the contract
-    let pubkey_return = contract.coordinatorPubKey().call().await?;
-
-    let ticket_cost_return = contract.ticketCost().call().await?;
-    let shipping_cost_return = contract.shippingCost().call().await?;
-
-    // Print the hex-encoded public key
-    println!("Chain ID: {}", chain_id);
-    println!("StealthGasStation contract: {}", contract_address);
-    println!("Coordinator URL: {}", get_default_url(chain_id));
-    println!("Ticket Cost: {}", u256_to_eth(ticket_cost_return._0));
-    println!("Shipping Cost: {}", u256_to_eth(shipping_cost_return._0));
-    println!("Coordinator PubKey: 0x{}", hex::encode(pubkey_return._0));
-
-    Ok(())
+) -> Result<ParamsReport, CliError> {
+    // The contract parameters need no tickets, so the (possibly encrypted) wallet stays closed
+    let client = StealthGasClient::without_wallet(ClientConfig {
+        chain_id,
+        rpc_url,
+        gas_station_address: contract_address,
+        ..Default::default()
+    });
+    client.params().await
 }
@@ -1,11 +1,12 @@
-use crate::commands::utils::get_default_url;
+use crate::client::{ClientConfig, StealthGasClient};
+use crate::encryption::Passphrase;
+use crate::error::CliError;
+use crate::output::wei;
+use crate::store::{TicketState, TicketStore};
 use alloy::primitives::{Address, U256};
-use dirs;
 use eth_stealth_gas_tickets::SignedTicket;
-use reqwest;
 use serde::{Deserialize, Serialize};
-use serde_json;
-use std::fs;
+use std::fmt;
 use std::str::FromStr;
 
 #[derive(Serialize, Deserialize)]
@@ -26,33 +27,225 @@ pub struct SpendRequest {
     pub spends: Vec<Spend>,
 }
 
+#[derive(Serialize)]
+pub struct RedeemedSpend {
+    #[serde(serialize_with = "wei")]
+    pub amount: U256,
+    pub receiver: Address,
+}
+
+#[derive(Serialize)]
+pub struct RedeemReport {
+    pub chain_id: u64,
+    pub tickets: usize,
+    pub spends: Vec<RedeemedSpend>,
+    pub status: u16,
+    /// Coordinator response body (parsed as JSON when possible)
+    pub response: serde_json::Value,
+}
+
+impl fmt::Display for RedeemReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Redemption successful!")?;
+        match &self.response {
+            serde_json::Value::String(text) => writeln!(f, "Response: {}", text),
+            response => writeln!(f, "Response: {}", response),
+        }
+    }
+}
+
+impl StealthGasClient {
+    /// Unspent finalized tickets of a stored batch (the most recent one if none is given)
+    pub fn redeemable_tickets(
+        &self,
+        batch_id: Option<&str>,
+    ) -> Result<Vec<SignedTicket>, CliError> {
+        let batch_id = self.resolve_batch(batch_id, None, |s| s == TicketState::Finalized)?;
+        let signed_tickets = self
+            .store
+            .batch(&batch_id)?
+            .signed_tickets(|s| s == TicketState::Finalized);
+        if signed_tickets.is_empty() {
+            return Err(CliError::InvalidInput(format!(
+                "Batch {} has no unspent finalized tickets (run `stealth-gas finalize` first)",
+                batch_id
+            )));
+        }
+        Ok(signed_tickets)
+    }
+
+    /// Tickets of a stored batch (the most recent one if none is given) left
+    /// redemption-submitted by a redemption whose outcome is unknown
+    pub fn submitted_tickets(&self, batch_id: Option<&str>) -> Result<Vec<SignedTicket>, CliError> {
+        let batch_id =
+            self.resolve_batch(batch_id, None, |s| s == TicketState::RedemptionSubmitted)?;
+        let signed_tickets = self
+            .store
+            .batch(&batch_id)?
+            .signed_tickets(|s| s == TicketState::RedemptionSubmitted);
+        if signed_tickets.is_empty() {
+            return Err(CliError::InvalidInput(format!(
+                "Batch {} has no tickets awaiting the outcome of a redemption",
+                batch_id
+            )));
+        }
+        Ok(signed_tickets)
+    }
+
+    /// Spend signed tickets through the coordinator, tracking their state in the wallet
+    pub async fn redeem(
+        &mut self,
+        signed_tickets: Vec<SignedTicket>,
+        spends: Vec<Spend>,
+    ) -> Result<RedeemReport, CliError> {
+        self.submit_redemption(signed_tickets, spends, TicketState::Finalized)
+            .await
+    }
+
+    /// Resend a redemption whose outcome is unknown (e.g. the connection dropped after the
+    /// request was sent). If the first request went through, the coordinator rejects the
+    /// tickets as already spent and they stay redemption-submitted
+    pub async fn retry_redemption(
+        &mut self,
+        signed_tickets: Vec<SignedTicket>,
+        spends: Vec<Spend>,
+    ) -> Result<RedeemReport, CliError> {
+        self.submit_redemption(signed_tickets, spends, TicketState::RedemptionSubmitted)
+            .await
+    }
+
+    async fn submit_redemption(
+        &mut self,
+        signed_tickets: Vec<SignedTicket>,
+        spends: Vec<Spend>,
+        expected: TicketState,
+    ) -> Result<RedeemReport, CliError> {
+        // Tickets known to the wallet must be in the expected state (unspent for a new redemption)
+        for signed in &signed_tickets {
+            if let Some(ticket) = self.store.find_signed_mut(signed) {
+                if ticket.state != expected {
+                    let hint = match ticket.state {
+                        TicketState::RedemptionSubmitted => {
+                            " (its last redemption may not have reached the coordinator, resend it with `stealth-gas redeem --retry`)"
+                        }
+                        _ => "",
+                    };
+                    return Err(CliError::InvalidInput(format!(
+                        "Ticket {} is {} and cannot be redeemed{}",
+                        ticket.unsigned.id, ticket.state, hint
+                    )));
+                }
+            }
+        }
+        let url = self.coordinator_url()?;
+
+        // Create spend request
+        let spend_request = SpendRequest {
+            signatures: signed_tickets,
+            spends,
+        };
+
+        // Mark the tickets as submitted before sending, so an interrupted request is never retried blindly
+        set_state(
+            &mut self.store,
+            &spend_request.signatures,
+            TicketState::RedemptionSubmitted,
+        )?;
+        self.store.save()?;
+
+        // Send POST request
+        let client = reqwest::Client::new();
+        let response = match client
+            .post(format!("{}/redeem", url))
+            .json(&spend_request)
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            // The request never left this machine, so the tickets are still spendable
+            Err(e) if e.is_connect() || e.is_builder() => {
+                set_state(
+                    &mut self.store,
+                    &spend_request.signatures,
+                    TicketState::Finalized,
+                )?;
+                self.store.save()?;
+                return Err(e.into());
+            }
+            Err(e) => {
+                return Err(CliError::Coordinator(format!(
+                    "Coordinator request failed after it was sent: {}. The tickets stay redemption-submitted; resend them with `stealth-gas redeem --retry` and the same spends",
+                    e
+                )))
+            }
+        };
+
+        let status = response.status();
+        if status.is_success() {
+            set_state(
+                &mut self.store,
+                &spend_request.signatures,
+                TicketState::Spent,
+            )?;
+            self.store.save()?;
+            let body = response.text().await?;
+            Ok(RedeemReport {
+                chain_id: self.chain_id(),
+                tickets: spend_request.signatures.len(),
+                spends: spend_request
+                    .spends
+                    .iter()
+                    .map(|s| RedeemedSpend {
+                        amount: s.amount,
+                        receiver: s.receiver,
+                    })
+                    .collect(),
+                status: status.as_u16(),
+                response: serde_json::from_str(&body).unwrap_or(serde_json::Value::String(body)),
+            })
+        } else {
+            // The coordinator rejected a new request, so the tickets are still spendable. A
+            // rejected retry may mean the first request spent them, so they stay submitted
+            if expected == TicketState::Finalized {
+                set_state(
+                    &mut self.store,
+                    &spend_request.signatures,
+                    TicketState::Finalized,
+                )?;
+                self.store.save()?;
+            }
+            Err(CliError::Coordinator(format!(
+                "Redemption failed with status {}: {}",
+                status,
+                response.text().await?
+            )))
+        }
+    }
+}
+
 pub async fn run(
     url: Option<String>,
     input: Option<String>,
+    batch: Option<String>,
     spends_json: String,
+    retry: bool,
     chain_id: Option<u64>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // Get chain ID and defaults
-    let chain_id = chain_id.unwrap_or(17000);
-
-    // Use provided values or defaults
-    let url = url.unwrap_or(get_default_url(chain_id));
-    let mut input_path = input.unwrap_or_else(|| "".to_string());
-
-    // If input path is empty, use default path in ~/.stealthereum
-    if input_path.is_empty() {
-        let home_dir = dirs::home_dir().expect("Could not find home directory");
-        let stealth_dir = home_dir.join(".stealthereum");
-        input_path = stealth_dir
-            .join(format!("finalized_tickets_{}.json", chain_id))
-            .to_str()
-            .expect("Failed to convert path to string")
-            .to_string();
-    }
+    passphrase: Passphrase,
+) -> Result<RedeemReport, CliError> {
+    let mut client = StealthGasClient::new(ClientConfig {
+        chain_id,
+        coordinator_url: url,
+        passphrase,
+        ..Default::default()
+    })?;
 
-    // Load signed tickets
-    let signed_tickets: Vec<SignedTicket> =
-        serde_json::from_str(&fs::read_to_string(&input_path)?)?;
+    // Load signed tickets from the input file or from the unspent (or, when retrying, the
+    // submitted) tickets of a stored batch
+    let signed_tickets: Vec<SignedTicket> = match input {
+        Some(input_path) => client.store().read_json_file(&input_path)?,
+        None if retry => client.submitted_tickets(batch.as_deref())?,
+        None => client.redeemable_tickets(batch.as_deref())?,
+    };
 
     // Load spends from raw JSON
     let spends: Vec<SpendInput> = serde_json::from_str(&spends_json)?;
@@ -60,33 +253,32 @@ pub async fn run(
     // Convert SpendInput to Spend
     let spends: Vec<Spend> = spends
         .into_iter()
-        .map(|s| Spend {
-            amount: U256::from_str(&s.amount).unwrap(),
-            receiver: s.receiver,
+        .map(|s| {
+            Ok(Spend {
+                amount: U256::from_str(&s.amount).map_err(|_| {
+                    CliError::InvalidInput(format!("Invalid spend amount: {}", s.amount))
+                })?,
+                receiver: s.receiver,
+            })
         })
-        .collect();
+        .collect::<Result<_, CliError>>()?;
 
-    // Create spend request
-    let spend_request = SpendRequest {
-        signatures: signed_tickets,
-        spends,
-    };
-
-    // Send POST request
-    let client = reqwest::Client::new();
-    let response = client
-        .post(format!("{}/redeem", url))
-        .json(&spend_request)
-        .send()
-        .await?;
-
-    if response.status().is_success() {
-        println!("Redemption successful!");
-        println!("Response: {}", response.text().await?);
+    if retry {
+        client.retry_redemption(signed_tickets, spends).await
     } else {
-        println!("Redemption failed with status: {}", response.status());
-        println!("Error: {}", response.text().await?);
+        client.redeem(signed_tickets, spends).await
     }
+}
 
+fn set_state(
+    store: &mut TicketStore,
+    signed_tickets: &[SignedTicket],
+    state: TicketState,
+) -> Result<(), CliError> {
+    for signed in signed_tickets {
+        if let Some(ticket) = store.find_signed_mut(signed) {
+            ticket.transition(state, None, None)?;
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,232 @@
+use crate::commands::utils::{
+    get_default_contract_address, get_default_rpc, parse_address, parse_url, u256_to_eth,
+};
+use crate::encryption::Passphrase;
+use crate::error::CliError;
+use crate::output::opt_wei;
+use crate::store::{Batch, TicketState, TicketStore};
+use alloy::{primitives::U256, providers::ProviderBuilder, sol};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+sol! {
+    #[sol(rpc)]
+    interface IStealthGasStation {
+        function ticketCost() external view returns (uint256);
+    }
+}
+
+#[derive(Serialize)]
+pub struct StatusReport {
+    pub wallet: PathBuf,
+    pub chains: Vec<ChainStatus>,
+}
+
+#[derive(Serialize)]
+pub struct ChainStatus {
+    pub chain_id: u64,
+    #[serde(serialize_with = "opt_wei")]
+    pub ticket_cost: Option<U256>,
+    /// Why the ticket cost could not be fetched, if it could not
+    pub warning: Option<String>,
+    pub coordinators: Vec<CoordinatorStatus>,
+}
+
+#[derive(Serialize)]
+pub struct CoordinatorStatus {
+    pub pubkey: String,
+    pub batches: Vec<BatchStatus>,
+    pub redeemable: usize,
+    #[serde(serialize_with = "opt_wei")]
+    pub redeemable_value: Option<U256>,
+    /// Batches still waiting for the coordinator's SendGasTickets signatures
+    pub awaiting_signatures: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct BatchStatus {
+    pub id: String,
+    pub tickets: usize,
+    pub states: BTreeMap<TicketState, usize>,
+}
+
+impl fmt::Display for StatusReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.chains.is_empty() {
+            return writeln!(f, "No ticket batches in {}", self.wallet.display());
+        }
+
+        for chain in &self.chains {
+            writeln!(f, "Chain ID: {}", chain.chain_id)?;
+            if let Some(cost) = chain.ticket_cost {
+                writeln!(f, "Ticket Cost: {}", u256_to_eth(cost))?;
+            }
+            if let Some(warning) = &chain.warning {
+                writeln!(f, "Warning: {}", warning)?;
+            }
+
+            for coordinator in &chain.coordinators {
+                writeln!(
+                    f,
+                    "  Coordinator PubKey: {}",
+                    short_hex(&coordinator.pubkey)
+                )?;
+
+                for batch in &coordinator.batches {
+                    let counts: Vec<String> = batch
+                        .states
+                        .iter()
+                        .map(|(s, n)| format!("{} {}", n, s))
+                        .collect();
+                    writeln!(
+                        f,
+                        "    Batch {}: {} tickets ({})",
+                        batch.id,
+                        batch.tickets,
+                        counts.join(", ")
+                    )?;
+                }
+
+                match coordinator.redeemable_value {
+                    Some(value) => writeln!(
+                        f,
+                        "    Redeemable: {} tickets ({})",
+                        coordinator.redeemable,
+                        u256_to_eth(value)
+                    )?,
+                    None => writeln!(f, "    Redeemable: {} tickets", coordinator.redeemable)?,
+                }
+
+                if !coordinator.awaiting_signatures.is_empty() {
+                    writeln!(
+                        f,
+                        "    Awaiting SendGasTickets signatures: {}",
+                        coordinator.awaiting_signatures.join(", ")
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Summarize the wallet, optionally only for one chain. The RPC URL and contract address only
+/// apply to `settings_chain_id` (the selected chain, or the profile's); the others use the
+/// chain registry
+pub async fn run(
+    rpc_url: Option<String>,
+    contract_address: Option<String>,
+    chain_id: Option<u64>,
+    settings_chain_id: Option<u64>,
+    passphrase: Passphrase,
+) -> Result<StatusReport, CliError> {
+    let store = TicketStore::open(&passphrase)?;
+
+    // Group batches by chain and coordinator pubkey
+    let mut grouped: BTreeMap<u64, BTreeMap<&str, Vec<&Batch>>> = BTreeMap::new();
+    for batch in store
+        .batches
+        .iter()
+        .filter(|b| chain_id.is_none_or(|c| b.chain_id == c))
+    {
+        grouped
+            .entry(batch.chain_id)
+            .or_default()
+            .entry(&batch.pubkey)
+            .or_default()
+            .push(batch);
+    }
+
+    let mut chains = Vec::new();
+    for (chain_id, by_pubkey) in grouped {
+        // Fetch the ticket cost to value the redeemable tickets on this chain
+        let (rpc_url, contract_address) = if settings_chain_id == Some(chain_id) {
+            (rpc_url.as_deref(), contract_address.as_deref())
+        } else {
+            (None, None)
+        };
+        let (ticket_cost, warning) =
+            match fetch_ticket_cost(chain_id, rpc_url, contract_address).await {
+                Ok(cost) => (Some(cost), None),
+                Err(e) => (
+                    None,
+                    Some(format!(
+                        "could not fetch ticketCost() on chain {}: {}",
+                        chain_id, e
+                    )),
+                ),
+            };
+
+        let coordinators = by_pubkey
+            .into_iter()
+            .map(|(pubkey, batches)| {
+                let redeemable: usize = batches
+                    .iter()
+                    .map(|b| b.count(|s| s == TicketState::Finalized))
+                    .sum();
+                CoordinatorStatus {
+                    pubkey: pubkey.to_string(),
+                    batches: batches
+                        .iter()
+                        .map(|batch| BatchStatus {
+                            id: batch.id.clone(),
+                            tickets: batch.tickets.len(),
+                            states: TicketState::ALL
+                                .iter()
+                                .map(|s| (*s, batch.count(|t| t == *s)))
+                                .filter(|(_, n)| *n > 0)
+                                .collect(),
+                        })
+                        .collect(),
+                    redeemable,
+                    redeemable_value: ticket_cost.map(|cost| cost * U256::from(redeemable)),
+                    awaiting_signatures: batches
+                        .iter()
+                        .filter(|b| b.tickets.iter().any(|t| t.state.is_awaiting_coordinator()))
+                        .map(|b| b.id.clone())
+                        .collect(),
+                }
+            })
+            .collect();
+
+        chains.push(ChainStatus {
+            chain_id,
+            ticket_cost,
+            warning,
+            coordinators,
+        });
+    }
+
+    Ok(StatusReport {
+        wallet: store.path().to_path_buf(),
+        chains,
+    })
+}
+
+/// Onchain ticket cost of a chain, from the given endpoint and contract or the registry's
+async fn fetch_ticket_cost(
+    chain_id: u64,
+    rpc_url: Option<&str>,
+    contract_address: Option<&str>,
+) -> Result<U256, CliError> {
+    let rpc_url = match rpc_url {
+        Some(rpc_url) => rpc_url.to_string(),
+        None => get_default_rpc(chain_id)?,
+    };
+    let contract_address = match contract_address {
+        Some(address) => address.to_string(),
+        None => get_default_contract_address(chain_id)?,
+    };
+    let provider = ProviderBuilder::new().on_http(parse_url(&rpc_url)?);
+    let contract = IStealthGasStation::new(parse_address(&contract_address)?, provider);
+    Ok(contract.ticketCost().call().await?._0)
+}
+
+fn short_hex(value: &str) -> String {
+    if value.len() <= 18 {
+        return value.to_string();
+    }
+    format!("{}...{}", &value[..10], &value[value.len() - 8..])
+}
@@ -0,0 +1,418 @@
+use crate::client::{ClientConfig, StealthGasClient};
+use crate::encryption::Passphrase;
+use crate::error::CliError;
+use crate::signer::SignerOptions;
+use crate::store::TicketState;
+use alloy::{
+    consensus::Transaction,
+    network::{Ethereum, EthereumWallet, NetworkWallet, TransactionBuilder},
+    primitives::{Address, TxHash, U256},
+    providers::{utils::Eip1559Estimation, Provider, ProviderBuilder},
+    rpc::types::{TransactionReceipt, TransactionRequest},
+};
+use serde::Serialize;
+use std::fmt;
+
+/// Smallest fee increase (in percent) nodes accept for a replacement transaction
+pub const MIN_BUMP_PERCENT: u64 = 10;
+
+/// Gas of a plain ETH transfer, used by cancellations
+const TRANSFER_GAS: u64 = 21_000;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Replacement {
+    /// Rebroadcast the same transaction with higher fees
+    Speedup,
+    /// Replace the transaction with a zero-value self-transfer at the same nonce
+    Cancel,
+}
+
+/// Fee settings of a replacement transaction
+#[derive(Clone, Copy)]
+pub struct ReplaceOptions {
+    /// Minimum fee increase over the replaced transaction, in percent
+    pub bump_percent: u64,
+    /// EIP-1559 fee caps in wei per gas (bumped from the replaced transaction if unset)
+    pub max_fee_per_gas: Option<u128>,
+    pub max_priority_fee_per_gas: Option<u128>,
+}
+
+impl Default for ReplaceOptions {
+    fn default() -> Self {
+        ReplaceOptions {
+            bump_percent: 15,
+            max_fee_per_gas: None,
+            max_priority_fee_per_gas: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ReplaceReport {
+    pub action: Replacement,
+    pub chain_id: u64,
+    pub replaced: TxHash,
+    pub tx_hash: TxHash,
+    pub nonce: u64,
+    pub max_fee_per_gas: u128,
+    pub max_priority_fee_per_gas: u128,
+    /// Batches whose pending purchase now points at the replacement transaction
+    pub batches: Vec<String>,
+    pub tickets: usize,
+}
+
+impl fmt::Display for ReplaceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.action {
+            Replacement::Speedup => writeln!(f, "Sped up transaction {}", self.replaced)?,
+            Replacement::Cancel => writeln!(f, "Cancelling transaction {}", self.replaced)?,
+        }
+        writeln!(f, "Replacement: {} (nonce {})", self.tx_hash, self.nonce)?;
+        writeln!(
+            f,
+            "Max fee per gas: {} wei (priority {} wei)",
+            self.max_fee_per_gas, self.max_priority_fee_per_gas
+        )?;
+        writeln!(
+            f,
+            "Updated {} tickets in batch(es) {}",
+            self.tickets,
+            self.batches.join(", ")
+        )?;
+        writeln!(
+            f,
+            "Run `stealth-gas finalize` once it is mined to resolve the purchase"
+        )
+    }
+}
+
+impl StealthGasClient {
+    /// Replace a pending buy transaction of the wallet with a higher-fee copy (speedup) or a
+    /// zero-value self-transfer (cancel) at the same nonce, and record the replacement as the
+    /// purchase transaction of its tickets
+    pub async fn replace_purchase(
+        &mut self,
+        wallet: EthereumWallet,
+        tx_hash: TxHash,
+        action: Replacement,
+        options: ReplaceOptions,
+    ) -> Result<ReplaceReport, CliError> {
+        if options.bump_percent < MIN_BUMP_PERCENT {
+            return Err(CliError::InvalidInput(format!(
+                "--bump must be at least {}% for nodes to accept the replacement",
+                MIN_BUMP_PERCENT
+            )));
+        }
+        let chain_id = self.chain_id();
+        let batches: Vec<String> = self
+            .store
+            .batches
+            .iter()
+            .filter(|b| b.chain_id == chain_id)
+            .filter(|b| {
+                b.tickets
+                    .iter()
+                    .any(|t| t.pending_purchase_txs().contains(&tx_hash))
+            })
+            .map(|b| b.id.clone())
+            .collect();
+        if batches.is_empty() {
+            return Err(CliError::InvalidInput(format!(
+                "No pending purchase with transaction {} on chain {} in {}",
+                tx_hash,
+                chain_id,
+                self.store.path().display()
+            )));
+        }
+
+        let provider = self.provider()?;
+        if let Some(receipt) = provider.get_transaction_receipt(tx_hash).await? {
+            return Err(CliError::InvalidInput(format!(
+                "Transaction {} was already mined in block {} (run `stealth-gas finalize` to resolve it)",
+                tx_hash,
+                receipt
+                    .block_number
+                    .map_or("unknown".to_string(), |b| b.to_string())
+            )));
+        }
+        let Some(original) = provider.get_transaction_by_hash(tx_hash).await? else {
+            return Err(CliError::Rpc(format!(
+                "Transaction {} is unknown to the node (it may have been dropped from the mempool)",
+                tx_hash
+            )));
+        };
+        let from = NetworkWallet::<Ethereum>::default_signer_address(&wallet);
+        check_sender(tx_hash, original.from, from)?;
+
+        let estimate = provider.estimate_eip1559_fees(None).await?;
+        let (max_fee_per_gas, max_priority_fee_per_gas) = replacement_fees(
+            original.max_fee_per_gas(),
+            original.max_priority_fee_per_gas(),
+            estimate,
+            &options,
+        )?;
+
+        let request = match action {
+            Replacement::Speedup => TransactionRequest::default()
+                .with_kind(original.kind())
+                .with_value(original.value())
+                .with_input(original.input().clone())
+                .with_gas_limit(original.gas_limit()),
+            Replacement::Cancel => TransactionRequest::default()
+                .with_to(from)
+                .with_value(U256::ZERO)
+                .with_gas_limit(TRANSFER_GAS),
+        }
+        .with_from(from)
+        .with_nonce(original.nonce())
+        .with_max_fee_per_gas(max_fee_per_gas)
+        .with_max_priority_fee_per_gas(max_priority_fee_per_gas);
+
+        let signer_provider = ProviderBuilder::new()
+            .with_recommended_fillers()
+            .wallet(wallet)
+            .on_http(self.rpc_url()?);
+        let replacement = *signer_provider.send_transaction(request).await?.tx_hash();
+
+        // Record the replacement next to the transactions it replaces, so finalize resolves the
+        // tickets through whichever of them gets mined
+        let mut tickets = 0;
+        for batch_id in &batches {
+            let batch = self.store.batch_mut(batch_id)?;
+            for ticket in batch
+                .tickets
+                .iter_mut()
+                .filter(|t| t.pending_purchase_txs().contains(&tx_hash))
+            {
+                ticket.transition(TicketState::PurchaseTxPending, Some(replacement), None)?;
+                tickets += 1;
+            }
+        }
+        self.store.save()?;
+
+        Ok(ReplaceReport {
+            action,
+            chain_id,
+            replaced: tx_hash,
+            tx_hash: replacement,
+            nonce: original.nonce(),
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+            batches,
+            tickets,
+        })
+    }
+}
+
+/// Only the account that sent a transaction can replace it
+fn check_sender(tx_hash: TxHash, sender: Address, signer: Address) -> Result<(), CliError> {
+    if sender != signer {
+        return Err(CliError::InvalidInput(format!(
+            "Transaction {} was sent by {}, not by the signing account {}",
+            tx_hash, sender, signer
+        )));
+    }
+    Ok(())
+}
+
+/// Fee caps of a replacement: the given ones, else the original's bumped by the minimum
+/// percentage or the current estimate, whichever is higher. Nodes only accept a replacement
+/// that raises both fee caps by that percentage (a legacy original has one fee for both)
+fn replacement_fees(
+    original_max_fee: u128,
+    original_priority_fee: Option<u128>,
+    estimate: Eip1559Estimation,
+    options: &ReplaceOptions,
+) -> Result<(u128, u128), CliError> {
+    let bump = |fee: u128| fee + (fee * options.bump_percent as u128).div_ceil(100);
+    let min_max_fee = bump(original_max_fee);
+    let min_priority_fee = bump(original_priority_fee.unwrap_or(original_max_fee));
+    let max_fee_per_gas = options
+        .max_fee_per_gas
+        .unwrap_or(min_max_fee.max(estimate.max_fee_per_gas));
+    let max_priority_fee_per_gas = options.max_priority_fee_per_gas.unwrap_or(
+        min_priority_fee
+            .max(estimate.max_priority_fee_per_gas)
+            .min(max_fee_per_gas),
+    );
+    if max_fee_per_gas < min_max_fee || max_priority_fee_per_gas < min_priority_fee {
+        return Err(CliError::InvalidInput(format!(
+            "The replacement needs a max fee per gas of at least {} wei and a priority fee of at least {} wei ({}% above the original)",
+            min_max_fee, min_priority_fee, options.bump_percent
+        )));
+    }
+    if max_priority_fee_per_gas > max_fee_per_gas {
+        return Err(CliError::InvalidInput(format!(
+            "--max-priority-fee ({} wei) must not exceed --max-fee-per-gas ({} wei)",
+            max_priority_fee_per_gas, max_fee_per_gas
+        )));
+    }
+    Ok((max_fee_per_gas, max_priority_fee_per_gas))
+}
+
+/// State of the tickets of a mined purchase transaction: a reverted purchase and a mined
+/// `tx cancel` (a self-transfer) both leave them unbought
+pub(crate) fn purchase_outcome(receipt: &TransactionReceipt) -> TicketState {
+    let cancelled = receipt.to == Some(receipt.from);
+    if receipt.status() && !cancelled {
+        TicketState::Purchased
+    } else {
+        TicketState::Failed
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    action: Replacement,
+    tx_hash: String,
+    rpc_url: Option<String>,
+    signer: SignerOptions,
+    chain_id: Option<u64>,
+    options: ReplaceOptions,
+    passphrase: Passphrase,
+) -> Result<ReplaceReport, CliError> {
+    let tx_hash: TxHash = tx_hash.parse().map_err(|e| {
+        CliError::InvalidInput(format!("Invalid transaction hash {}: {}", tx_hash, e))
+    })?;
+    let mut client = StealthGasClient::new(ClientConfig {
+        chain_id,
+        rpc_url,
+        passphrase,
+        ..Default::default()
+    })?;
+
+    // The replacement must be signed by the account that sent the original transaction
+    let wallet = signer.wallet().await?;
+
+    client
+        .replace_purchase(wallet, tx_hash, action, options)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::consensus::{Eip658Value, Receipt, ReceiptEnvelope, ReceiptWithBloom};
+
+    const GWEI: u128 = 1_000_000_000;
+
+    fn estimate(max_fee_per_gas: u128, max_priority_fee_per_gas: u128) -> Eip1559Estimation {
+        Eip1559Estimation {
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+        }
+    }
+
+    fn receipt(success: bool, from: Address, to: Address) -> TransactionReceipt {
+        TransactionReceipt {
+            inner: ReceiptEnvelope::Eip1559(ReceiptWithBloom {
+                receipt: Receipt {
+                    status: Eip658Value::Eip658(success),
+                    cumulative_gas_used: TRANSFER_GAS,
+                    logs: Vec::new(),
+                },
+                logs_bloom: Default::default(),
+            }),
+            transaction_hash: TxHash::repeat_byte(1),
+            transaction_index: Some(0),
+            block_hash: None,
+            block_number: Some(1),
+            gas_used: TRANSFER_GAS,
+            effective_gas_price: GWEI,
+            blob_gas_used: None,
+            blob_gas_price: None,
+            from,
+            to: Some(to),
+            contract_address: None,
+            authorization_list: None,
+        }
+    }
+
+    #[test]
+    fn replacement_fees_bump_the_original_or_follow_the_market() {
+        let options = ReplaceOptions::default();
+        // 15% above 10 and 2 gwei, rounded up
+        assert_eq!(
+            replacement_fees(10 * GWEI, Some(2 * GWEI), estimate(GWEI, GWEI), &options).unwrap(),
+            (11_500_000_000, 2_300_000_000)
+        );
+        assert_eq!(
+            replacement_fees(101, Some(101), estimate(0, 0), &options).unwrap(),
+            (117, 117)
+        );
+        // A higher market estimate wins; the priority fee never exceeds the max fee
+        assert_eq!(
+            replacement_fees(
+                10 * GWEI,
+                Some(2 * GWEI),
+                estimate(30 * GWEI, 40 * GWEI),
+                &options
+            )
+            .unwrap(),
+            (30 * GWEI, 30 * GWEI)
+        );
+        // A legacy original pays its gas price as priority fee too
+        assert_eq!(
+            replacement_fees(10 * GWEI, None, estimate(GWEI, GWEI), &options).unwrap(),
+            (11_500_000_000, 11_500_000_000)
+        );
+    }
+
+    #[test]
+    fn replacement_fees_reject_caps_below_the_minimum_bump() {
+        let options = ReplaceOptions {
+            bump_percent: 10,
+            max_fee_per_gas: Some(10 * GWEI),
+            max_priority_fee_per_gas: None,
+        };
+        let error = replacement_fees(10 * GWEI, Some(GWEI), estimate(0, 0), &options).unwrap_err();
+        assert!(
+            matches!(&error, CliError::InvalidInput(m) if m.contains("at least 11000000000 wei")),
+            "{}",
+            error
+        );
+
+        let options = ReplaceOptions {
+            max_fee_per_gas: Some(20 * GWEI),
+            max_priority_fee_per_gas: Some(21 * GWEI),
+            ..options
+        };
+        let error = replacement_fees(10 * GWEI, Some(GWEI), estimate(0, 0), &options).unwrap_err();
+        assert!(
+            matches!(&error, CliError::InvalidInput(m) if m.contains("must not exceed")),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn only_the_sender_can_replace_a_transaction() {
+        let sender = Address::repeat_byte(1);
+        assert!(check_sender(TxHash::ZERO, sender, sender).is_ok());
+        let error = check_sender(TxHash::ZERO, sender, Address::repeat_byte(2)).unwrap_err();
+        assert!(
+            matches!(&error, CliError::InvalidInput(m) if m.contains("not by the signing account")),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn a_mined_cancel_fails_the_purchase() {
+        let from = Address::repeat_byte(1);
+        let gas_station = Address::repeat_byte(2);
+        assert_eq!(
+            purchase_outcome(&receipt(true, from, gas_station)),
+            TicketState::Purchased
+        );
+        assert_eq!(
+            purchase_outcome(&receipt(false, from, gas_station)),
+            TicketState::Failed
+        );
+        assert_eq!(
+            purchase_outcome(&receipt(true, from, from)),
+            TicketState::Failed
+        );
+    }
+}
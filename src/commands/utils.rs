@@ -1,34 +1,199 @@
-pub fn get_default_contract_address(chain_id: u64) -> String {
-    match chain_id {
-        17000 => "0x943285f1a29281e59514fF35Dc16E5a14E123a27".to_string(),
-        _ => panic!("Unsupported chain ID: {}", chain_id),
+use crate::chains;
+use crate::error::CliError;
+use alloy::{
+    hex,
+    primitives::{
+        utils::{parse_units, Unit},
+        Address, U256,
+    },
+    transports::http::reqwest::Url,
+};
+use eth_stealth_gas_tickets::TicketsVerifier;
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+pub fn get_default_contract_address(chain_id: u64) -> Result<String, CliError> {
+    let chain = chains::lookup(chain_id)?;
+    chain.require("gas_station_address", &chain.gas_station_address)
+}
+
+pub fn get_default_url(chain_id: u64) -> Result<String, CliError> {
+    let chain = chains::lookup(chain_id)?;
+    chain.require("coordinator_url", &chain.coordinator_url)
+}
+
+pub fn get_default_pubkey(chain_id: u64) -> Result<String, CliError> {
+    let chain = chains::lookup(chain_id)?;
+    chain.require("coordinator_pubkey", &chain.coordinator_pubkey)
+}
+
+pub fn get_default_rpc(chain_id: u64) -> Result<String, CliError> {
+    let chain = chains::lookup(chain_id)?;
+    chain.require("rpc_url", &chain.rpc_url)
+}
+
+pub fn get_default_tickets_number(chain_id: u64) -> Result<usize, CliError> {
+    let chain = chains::lookup(chain_id)?;
+    chain.require("default_tickets", &chain.default_tickets)
+}
+
+pub fn get_stealth_dir() -> Result<PathBuf, CliError> {
+    let home_dir = dirs::home_dir()
+        .ok_or_else(|| CliError::Storage("Could not find home directory".to_string()))?;
+    let stealth_dir = home_dir.join(".stealthereum");
+    if !stealth_dir.exists() {
+        fs::create_dir_all(&stealth_dir)?;
     }
+    Ok(stealth_dir)
 }
 
-pub fn get_default_url(chain_id: u64) -> String {
-    match chain_id {
-        17000 => "https://0000000000.org".to_string(),
-        _ => panic!("Unsupported chain ID: {}", chain_id),
+/// Refuse to clobber an existing output file
+pub fn ensure_new_file(path: &str) -> Result<(), CliError> {
+    if Path::new(path).exists() {
+        return Err(CliError::FileConflict(format!(
+            "Output file {} already exists",
+            path
+        )));
     }
+    Ok(())
+}
+
+/// Read a file given on the command line, reporting a missing or unreadable file as bad input
+pub fn read_input_file(path: &str) -> Result<String, CliError> {
+    fs::read_to_string(path)
+        .map_err(|e| CliError::InvalidInput(format!("Failed to read input file {}: {}", path, e)))
 }
 
-pub fn get_default_pubkey(chain_id: u64) -> String {
-    match chain_id {
-        17000 => "0x01000100e1dcd2c6b4eaefaa8360bfdab9810686e33f444818561c9f7efeb39cd577015bf9f98c56b5785315d8193f276f638d2f575842a62847e965fcc2e05c6b7242f21363158af9dbabb5642f40c121c0a9adabc1ea935f0ef12f68b794d717b78ebf1d3e4e4f5185fc8cb9fcc7b8e39c7e276ed6ac37e934a0b2bda1aa7193ce55d849deb94a4545d8d228d66a1dba96d99c236148bcc6affb2c898e869b90cbcf62b8397a93f3345f65dcbe83ae03c9ecc5aa934fc997898547136963266bb1eb29eaf62611c608b428bd72ad15614b561fdf3d16cccc1e8ee1e3b917529ee22acc8ff2f83c0114c809302381186ea6fc138060fba4bc03265b64d854bd9f536221".to_string(),
-        _ => panic!("Unsupported chain ID: {}", chain_id),
+/// Parse a JSON file given on the command line
+pub fn parse_json_file<T: DeserializeOwned>(path: &str, contents: &[u8]) -> Result<T, CliError> {
+    serde_json::from_slice(contents)
+        .map_err(|e| CliError::InvalidInput(format!("Invalid JSON in {}: {}", path, e)))
+}
+
+// Convert U256 (wei) to a human-readable ETH amount as a string
+pub fn u256_to_eth(wei: U256) -> String {
+    // 10^18 in U256
+    let eth_unit = U256::from(10).pow(U256::from(18));
+
+    // Get the whole part and the remainder
+    let whole = wei / eth_unit;
+    let remainder = wei % eth_unit;
+
+    // Convert remainder to a fractional part by scaling it to 18 decimal places
+    let fractional_str = format!("{:018}", remainder);
+
+    // Trim trailing zeros from the fractional part
+    format!("{}.{} ETH", whole, fractional_str.trim_end_matches('0'))
+}
+
+/// Parse a coordinator public key, rejecting malformed hex before it reaches the verifier
+pub fn parse_pubkey(pubkey_hex: &str) -> Result<TicketsVerifier, CliError> {
+    let raw = pubkey_hex.trim_start_matches("0x");
+    if raw.len() <= 8 || hex::decode(raw).is_err() {
+        return Err(CliError::InvalidInput(format!(
+            "Invalid coordinator public key: {}",
+            pubkey_hex
+        )));
     }
+    Ok(TicketsVerifier::from_hex_string(pubkey_hex)?)
+}
+
+pub fn parse_address(address: &str) -> Result<Address, CliError> {
+    Address::from_str(address)
+        .map_err(|e| CliError::InvalidInput(format!("Invalid address {}: {}", address, e)))
 }
 
-pub fn get_default_rpc(chain_id: u64) -> String {
-    match chain_id {
-        17000 => "https://ethereum-holesky.publicnode.com".to_string(),
-        _ => panic!("Unsupported chain ID: {}", chain_id),
+/// Parse an amount in wei, or with a `gwei` / `eth` suffix (e.g. `30gwei`, `0.05eth`)
+pub fn parse_amount(amount: &str) -> Result<U256, CliError> {
+    let lower = amount.trim().to_lowercase();
+    let (number, unit) = if let Some(number) = lower.strip_suffix("gwei") {
+        (number, Unit::GWEI)
+    } else if let Some(number) = lower.strip_suffix("eth") {
+        (number, Unit::ETHER)
+    } else {
+        (lower.strip_suffix("wei").unwrap_or(&lower), Unit::WEI)
+    };
+    let number = number.trim();
+    if number.is_empty() {
+        return Err(CliError::InvalidInput(format!(
+            "Invalid amount {:?}: expected a number",
+            amount
+        )));
+    }
+    if number.starts_with('-') {
+        return Err(CliError::InvalidInput(format!(
+            "Invalid amount {}: must not be negative",
+            amount
+        )));
     }
+    // parse_units silently truncates digits below one wei
+    let decimals = number
+        .split_once('.')
+        .map_or(0, |(_, fraction)| fraction.trim_end_matches('0').len());
+    if decimals > unit.get() as usize {
+        return Err(CliError::InvalidInput(format!(
+            "Invalid amount {}: amounts are whole numbers of wei",
+            amount
+        )));
+    }
+    parse_units(number, unit.get())
+        .map(|units| units.get_absolute())
+        .map_err(|e| CliError::InvalidInput(format!("Invalid amount {}: {}", amount, e)))
+}
+
+/// Parse a fee per gas amount (see `parse_amount`), e.g. `30gwei`
+pub fn parse_fee_per_gas(amount: &str) -> Result<u128, CliError> {
+    u128::try_from(parse_amount(amount)?)
+        .map_err(|_| CliError::InvalidInput(format!("Fee per gas {} is too large", amount)))
+}
+
+pub fn parse_url(url: &str) -> Result<Url, CliError> {
+    url.parse()
+        .map_err(|e| CliError::InvalidInput(format!("Invalid URL {}: {}", url, e)))
 }
 
-pub fn get_default_tickets_number(chain_id: u64) -> usize {
-    match chain_id {
-        17000 => 10usize,
-        _ => panic!("Unsupported chain ID: {}", chain_id),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_amount_units() {
+        assert_eq!(parse_amount("1000").unwrap(), U256::from(1000));
+        assert_eq!(parse_amount("1000wei").unwrap(), U256::from(1000));
+        assert_eq!(
+            parse_amount("30gwei").unwrap(),
+            U256::from(30_000_000_000u64)
+        );
+        assert_eq!(
+            parse_amount("1.5 GWEI").unwrap(),
+            U256::from(1_500_000_000u64)
+        );
+        assert_eq!(
+            parse_amount("0.05eth").unwrap(),
+            U256::from(50_000_000_000_000_000u64)
+        );
+    }
+
+    #[test]
+    fn parse_amount_rejects_invalid_amounts() {
+        for amount in [
+            "",
+            "-1",
+            "-1gwei",
+            "abc",
+            "1.5wei",
+            "0.0000000001gwei",
+            "1btc",
+        ] {
+            assert!(parse_amount(amount).is_err(), "{}", amount);
+        }
+    }
+
+    #[test]
+    fn parse_fee_per_gas_fits_u128() {
+        assert_eq!(parse_fee_per_gas("2gwei").unwrap(), 2_000_000_000);
+        assert!(parse_fee_per_gas(&U256::MAX.to_string()).is_err());
     }
 }
@@ -1,25 +1,75 @@
-use crate::commands::utils::get_default_pubkey;
-use eth_stealth_gas_tickets::{SignedTicket, TicketsVerifier};
-use serde_json::from_reader;
-use std::fs::File;
-use std::io::BufReader;
-
-pub fn run(pubkey_hex: Option<String>, input_path: String, chain_id: Option<u64>) {
-    let chain_id = chain_id.unwrap_or(17000);
-    let pubkey_hex = pubkey_hex.unwrap_or(get_default_pubkey(chain_id));
-
-    let pubkey = TicketsVerifier::from_hex_string(&pubkey_hex).expect("Invalid public key");
-
-    // Read the input JSON file
-    let input_file = File::open(&input_path).expect("Failed to open input file");
-    let reader = BufReader::new(input_file);
-    let signed_tickets: Vec<SignedTicket> =
-        from_reader(reader).expect("Failed to parse signed tickets JSON");
-
-    // Verify the signed tickets
-    pubkey
-        .verify_signed_tickets(signed_tickets)
-        .expect("Ticket verification failed");
-
-    println!("Ticket verification passed!");
+use crate::client::{ClientConfig, StealthGasClient};
+use crate::encryption::Passphrase;
+use crate::error::CliError;
+use eth_stealth_gas_tickets::SignedTicket;
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Serialize)]
+pub struct VerifyReport {
+    pub chain_id: u64,
+    pub tickets: usize,
+    pub valid: bool,
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Ticket verification passed! ({} tickets)", self.tickets)
+    }
+}
+
+impl StealthGasClient {
+    /// Verify the finalized tickets of a stored batch (the most recent one if none is given)
+    pub fn verify(&self, batch_id: Option<&str>) -> Result<VerifyReport, CliError> {
+        let pubkey_hex = self.coordinator_pubkey()?;
+        let signed_tickets = self
+            .store
+            .select_batch(batch_id, self.chain_id(), Some(&pubkey_hex), |s| {
+                s.is_finalized()
+            })?
+            .signed_tickets(|s| s.is_finalized());
+        self.verify_tickets(signed_tickets)
+    }
+
+    /// Verify signed tickets against the coordinator pubkey
+    pub fn verify_tickets(
+        &self,
+        signed_tickets: Vec<SignedTicket>,
+    ) -> Result<VerifyReport, CliError> {
+        if signed_tickets.is_empty() {
+            return Err(CliError::InvalidInput(
+                "No finalized tickets to verify".to_string(),
+            ));
+        }
+
+        let count = signed_tickets.len();
+        self.verifier()?.verify_signed_tickets(signed_tickets)?;
+
+        Ok(VerifyReport {
+            chain_id: self.chain_id(),
+            tickets: count,
+            valid: true,
+        })
+    }
+}
+
+pub fn run(
+    pubkey_hex: Option<String>,
+    input_path: Option<String>,
+    batch: Option<String>,
+    chain_id: Option<u64>,
+    passphrase: Passphrase,
+) -> Result<VerifyReport, CliError> {
+    let client = StealthGasClient::new(ClientConfig {
+        chain_id,
+        coordinator_pubkey: pubkey_hex,
+        passphrase,
+        ..Default::default()
+    })?;
+
+    // Read the signed tickets from the input JSON file or from a stored batch
+    match input_path {
+        Some(input_path) => client.verify_tickets(client.store().read_json_file(&input_path)?),
+        None => client.verify(batch.as_deref()),
+    }
 }
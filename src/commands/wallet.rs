@@ -0,0 +1,109 @@
+use crate::encryption::Passphrase;
+use crate::error::CliError;
+use crate::store::TicketStore;
+use serde::Serialize;
+use std::fmt;
+use std::path::PathBuf;
+use zeroize::Zeroizing;
+
+#[derive(Serialize)]
+pub struct WalletReport {
+    pub wallet: PathBuf,
+    pub encrypted: bool,
+}
+
+impl fmt::Display for WalletReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.encrypted {
+            writeln!(f, "Encrypted wallet {}", self.wallet.display())
+        } else {
+            writeln!(
+                f,
+                "Decrypted wallet {} (tickets are now stored in plaintext)",
+                self.wallet.display()
+            )
+        }
+    }
+}
+
+/// Encrypt the wallet with a new passphrase (or change the passphrase of an encrypted wallet).
+/// The new passphrase comes from `new_passphrase` if given. Otherwise a plaintext wallet is
+/// encrypted with `passphrase`, and the new passphrase is prompted for if that is a prompt
+/// or the wallet is already encrypted
+pub fn encrypt(
+    passphrase: Passphrase,
+    new_passphrase: Option<Passphrase>,
+) -> Result<WalletReport, CliError> {
+    let store = TicketStore::open(&passphrase)?;
+    encrypt_store(store, &passphrase, new_passphrase)
+}
+
+fn encrypt_store(
+    mut store: TicketStore,
+    passphrase: &Passphrase,
+    new_passphrase: Option<Passphrase>,
+) -> Result<WalletReport, CliError> {
+    let new_passphrase = match new_passphrase {
+        Some(new_passphrase) => new_passphrase.resolve("")?,
+        None if !store.is_encrypted() && !passphrase.is_prompt() => passphrase.resolve("")?,
+        None => {
+            let first = Zeroizing::new(rpassword::prompt_password("New wallet passphrase:")?);
+            let second = Zeroizing::new(rpassword::prompt_password("Repeat passphrase:")?);
+            if first != second {
+                return Err(CliError::InvalidInput(
+                    "Passphrases do not match".to_string(),
+                ));
+            }
+            Passphrase::Value(first).resolve("")?
+        }
+    };
+
+    store.set_passphrase(Some(&new_passphrase))?;
+    store.save()?;
+
+    Ok(WalletReport {
+        wallet: store.path().to_path_buf(),
+        encrypted: true,
+    })
+}
+
+/// Store the wallet in plaintext again
+pub fn decrypt(passphrase: Passphrase) -> Result<WalletReport, CliError> {
+    let mut store = TicketStore::open(&passphrase)?;
+    store.set_passphrase(None)?;
+    store.save()?;
+
+    Ok(WalletReport {
+        wallet: store.path().to_path_buf(),
+        encrypted: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::temp_wallet;
+    use std::fs;
+
+    fn value(passphrase: &str) -> Passphrase {
+        Passphrase::Value(Zeroizing::new(passphrase.to_string()))
+    }
+
+    #[test]
+    fn encrypt_changes_the_passphrase_to_the_new_one() {
+        let path = temp_wallet();
+        let new_file = path.with_file_name("new-passphrase");
+        fs::write(&new_file, "new\n").unwrap();
+
+        // A plaintext wallet is encrypted with the passphrase options
+        let store = TicketStore::open_at(&path, &value("old")).unwrap();
+        encrypt_store(store, &value("old"), None).unwrap();
+        let store = TicketStore::open_at(&path, &value("old")).unwrap();
+        assert!(store.is_encrypted());
+
+        encrypt_store(store, &value("old"), Some(Passphrase::File(new_file))).unwrap();
+        assert!(TicketStore::open_at(&path, &value("old")).is_err());
+        assert!(TicketStore::open_at(&path, &value("new")).is_ok());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
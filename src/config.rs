@@ -0,0 +1,189 @@
+use crate::commands::utils::get_stealth_dir;
+use crate::error::CliError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+const CONFIG_FILE: &str = "config.toml";
+
+/// User settings stored in ~/.stealthereum/config.toml
+#[derive(Serialize, Deserialize, Default)]
+pub struct Config {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// A named set of defaults; any CLI flag overrides it and it overrides the chain registry
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct Profile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain_id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpc_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gas_station_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coordinator_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coordinator_pubkey: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+}
+
+const PROFILE_KEYS: [&str; 6] = [
+    "chain_id",
+    "rpc_url",
+    "gas_station_address",
+    "coordinator_url",
+    "coordinator_pubkey",
+    "account",
+];
+
+impl Config {
+    pub fn path() -> Result<PathBuf, CliError> {
+        Ok(get_stealth_dir()?.join(CONFIG_FILE))
+    }
+
+    pub fn load() -> Result<Self, CliError> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        toml::from_str(&fs::read_to_string(&path)?)
+            .map_err(|e| CliError::Config(format!("Invalid config file {}: {}", path.display(), e)))
+    }
+
+    pub fn save(&self) -> Result<(), CliError> {
+        fs::write(Self::path()?, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Name of the profile to use: the one passed with --profile, else the configured default
+    pub fn profile_name(&self, name: Option<&str>) -> Option<String> {
+        name.map(str::to_string)
+            .or_else(|| self.default_profile.clone())
+    }
+
+    /// Resolve the active profile (an empty profile if none is selected)
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile, CliError> {
+        let Some(name) = self.profile_name(name) else {
+            return Ok(Profile::default());
+        };
+        self.profiles.get(&name).cloned().ok_or_else(|| {
+            let known: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            CliError::Config(format!(
+                "Profile {} not found in {} (known profiles: {})",
+                name,
+                CONFIG_FILE,
+                if known.is_empty() {
+                    "none".to_string()
+                } else {
+                    known.join(", ")
+                }
+            ))
+        })
+    }
+}
+
+impl Profile {
+    /// The profile as it applies to a command on `chain_id`. The RPC URL, contract and
+    /// coordinator of a profile pinned to another chain belong to that chain, so only its
+    /// account is kept
+    pub fn for_chain(self, chain_id: Option<u64>) -> Profile {
+        match (chain_id, self.chain_id) {
+            (Some(chain_id), Some(own)) if chain_id != own => Profile {
+                chain_id: Some(own),
+                account: self.account,
+                ..Default::default()
+            },
+            _ => self,
+        }
+    }
+
+    pub fn set(&mut self, key: &str, value: String) -> Result<(), CliError> {
+        match key {
+            "chain_id" => {
+                self.chain_id =
+                    Some(value.parse().map_err(|_| {
+                        CliError::InvalidInput(format!("Invalid chain_id: {}", value))
+                    })?)
+            }
+            "rpc_url" => self.rpc_url = Some(value),
+            "gas_station_address" => self.gas_station_address = Some(value),
+            "coordinator_url" => self.coordinator_url = Some(value),
+            "coordinator_pubkey" => self.coordinator_pubkey = Some(value),
+            "account" => self.account = Some(value),
+            _ => return Err(unknown_key(key)),
+        }
+        Ok(())
+    }
+
+    pub fn unset(&mut self, key: &str) -> Result<(), CliError> {
+        match key {
+            "chain_id" => self.chain_id = None,
+            "rpc_url" => self.rpc_url = None,
+            "gas_station_address" => self.gas_station_address = None,
+            "coordinator_url" => self.coordinator_url = None,
+            "coordinator_pubkey" => self.coordinator_pubkey = None,
+            "account" => self.account = None,
+            _ => return Err(unknown_key(key)),
+        }
+        Ok(())
+    }
+}
+
+fn unknown_key(key: &str) -> CliError {
+    CliError::InvalidInput(format!(
+        "Unknown config key: {} (expected default_profile or one of {})",
+        key,
+        PROFILE_KEYS.join(", ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn holesky() -> Profile {
+        Profile {
+            chain_id: Some(17000),
+            rpc_url: Some("https://holesky.example".to_string()),
+            gas_station_address: Some("0x01".to_string()),
+            coordinator_url: Some("https://coordinator.example".to_string()),
+            coordinator_pubkey: Some("0x02".to_string()),
+            account: Some("treasury".to_string()),
+        }
+    }
+
+    #[test]
+    fn profile_applies_to_its_own_chain() {
+        for chain_id in [None, Some(17000)] {
+            let profile = holesky().for_chain(chain_id);
+            assert_eq!(profile.rpc_url.as_deref(), Some("https://holesky.example"));
+            assert_eq!(profile.coordinator_pubkey.as_deref(), Some("0x02"));
+        }
+    }
+
+    #[test]
+    fn profile_keeps_only_the_account_on_another_chain() {
+        let profile = holesky().for_chain(Some(11155111));
+        assert_eq!(profile.account.as_deref(), Some("treasury"));
+        assert!(profile.rpc_url.is_none());
+        assert!(profile.gas_station_address.is_none());
+        assert!(profile.coordinator_url.is_none());
+        assert!(profile.coordinator_pubkey.is_none());
+    }
+
+    #[test]
+    fn chainless_profile_applies_to_any_chain() {
+        let profile = Profile {
+            chain_id: None,
+            ..holesky()
+        }
+        .for_chain(Some(11155111));
+        assert_eq!(profile.rpc_url.as_deref(), Some("https://holesky.example"));
+    }
+}
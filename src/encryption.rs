@@ -0,0 +1,293 @@
+use crate::error::CliError;
+use alloy::hex;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use zeroize::{Zeroize, Zeroizing};
+
+/// Environment variable holding the wallet passphrase for non-interactive use
+pub const PASSPHRASE_ENV: &str = "STEALTH_GAS_PASSPHRASE";
+/// Environment variable holding the passphrase `wallet encrypt` sets, to change it without a prompt
+pub const NEW_PASSPHRASE_ENV: &str = "STEALTH_GAS_NEW_PASSPHRASE";
+
+const KDF: &str = "scrypt";
+const CIPHER: &str = "xchacha20poly1305";
+const SALT_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+
+/// Where to get the passphrase of an encrypted wallet, asked for only when one is needed
+#[derive(Clone, Default)]
+pub enum Passphrase {
+    /// No passphrase: opening an encrypted wallet fails instead of blocking on a prompt
+    #[default]
+    None,
+    /// Prompt for it on the terminal
+    Prompt,
+    /// Read it from the first line of a file
+    File(PathBuf),
+    /// Use the given value (e.g. read from STEALTH_GAS_PASSPHRASE)
+    Value(Zeroizing<String>),
+}
+
+impl Passphrase {
+    /// The passphrase file if given, else STEALTH_GAS_PASSPHRASE if set, else a prompt
+    pub fn from_env_or_file(file: Option<PathBuf>) -> Self {
+        Self::from_source(file, PASSPHRASE_ENV).unwrap_or(Passphrase::Prompt)
+    }
+
+    /// The new passphrase file if given, else STEALTH_GAS_NEW_PASSPHRASE if set
+    pub fn new_from_env_or_file(file: Option<PathBuf>) -> Option<Self> {
+        Self::from_source(file, NEW_PASSPHRASE_ENV)
+    }
+
+    fn from_source(file: Option<PathBuf>, env: &str) -> Option<Self> {
+        match file {
+            Some(path) => Some(Passphrase::File(path)),
+            None => std::env::var(env)
+                .ok()
+                .map(|value| Passphrase::Value(Zeroizing::new(value))),
+        }
+    }
+
+    pub fn is_prompt(&self) -> bool {
+        matches!(self, Passphrase::Prompt)
+    }
+
+    pub fn resolve(&self, prompt: &str) -> Result<Zeroizing<String>, CliError> {
+        let passphrase = match self {
+            Passphrase::None => {
+                return Err(CliError::InvalidInput(
+                    "The file is encrypted but no passphrase was given (set ClientConfig::passphrase, --passphrase-file or STEALTH_GAS_PASSPHRASE)"
+                        .to_string(),
+                ))
+            }
+            Passphrase::Prompt => Zeroizing::new(rpassword::prompt_password(prompt)?),
+            Passphrase::File(path) => {
+                let contents = Zeroizing::new(fs::read_to_string(path).map_err(|e| {
+                    CliError::InvalidInput(format!(
+                        "Failed to read passphrase file {}: {}",
+                        path.display(),
+                        e
+                    ))
+                })?);
+                Zeroizing::new(contents.lines().next().unwrap_or_default().to_string())
+            }
+            Passphrase::Value(value) => value.clone(),
+        };
+        if passphrase.is_empty() {
+            return Err(CliError::InvalidInput(
+                "The wallet passphrase must not be empty".to_string(),
+            ));
+        }
+        Ok(passphrase)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct KdfParams {
+    log_n: u8,
+    r: u32,
+    p: u32,
+}
+
+/// JSON envelope of an encrypted file: scrypt key derivation and XChaCha20-Poly1305 AEAD,
+/// in the spirit of the Ethereum keystore format
+#[derive(Serialize, Deserialize)]
+pub struct EncryptedFile {
+    version: u8,
+    kdf: String,
+    kdfparams: KdfParams,
+    salt: String,
+    cipher: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+impl EncryptedFile {
+    /// Parse file contents as an encrypted envelope, or None if it is a plaintext file
+    pub fn parse(contents: &str) -> Option<Self> {
+        serde_json::from_str(contents).ok()
+    }
+
+    /// Derive the key from the passphrase and decrypt, returning the cipher for re-encryption
+    pub fn unlock(&self, passphrase: &str) -> Result<(Cipher, Zeroizing<Vec<u8>>), CliError> {
+        if self.version != 1 || self.kdf != KDF || self.cipher != CIPHER {
+            return Err(CliError::Storage(format!(
+                "Unsupported encryption format ({} v{}, {})",
+                self.kdf, self.version, self.cipher
+            )));
+        }
+        let salt = decode(&self.salt)?;
+        let cipher = Cipher::derive(passphrase, salt, self.kdfparams)?;
+        let plaintext = cipher.decrypt(self)?;
+        Ok((cipher, plaintext))
+    }
+}
+
+/// Key derived from the wallet passphrase, kept in memory while the wallet is open
+#[derive(Clone)]
+pub struct Cipher {
+    params: KdfParams,
+    salt: Vec<u8>,
+    key: [u8; 32],
+}
+
+impl Cipher {
+    /// Derive a key for a new passphrase with a fresh random salt
+    pub fn new(passphrase: &str) -> Result<Self, CliError> {
+        let mut salt = vec![0u8; SALT_LEN];
+        thread_rng().fill_bytes(&mut salt);
+        Self::derive(
+            passphrase,
+            salt,
+            KdfParams {
+                log_n: scrypt::Params::RECOMMENDED_LOG_N,
+                r: scrypt::Params::RECOMMENDED_R,
+                p: scrypt::Params::RECOMMENDED_P,
+            },
+        )
+    }
+
+    fn derive(passphrase: &str, salt: Vec<u8>, params: KdfParams) -> Result<Self, CliError> {
+        let scrypt_params = scrypt::Params::new(params.log_n, params.r, params.p, 32)
+            .map_err(|e| CliError::Storage(format!("Invalid scrypt parameters: {}", e)))?;
+        let mut key = [0u8; 32];
+        scrypt::scrypt(passphrase.as_bytes(), &salt, &scrypt_params, &mut key)
+            .map_err(|e| CliError::Storage(format!("Key derivation failed: {}", e)))?;
+        Ok(Cipher { params, salt, key })
+    }
+
+    /// Encrypt under a fresh random nonce
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<EncryptedFile, CliError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = XChaCha20Poly1305::new(&self.key.into())
+            .encrypt(XNonce::from_slice(&nonce), plaintext)
+            .map_err(|_| CliError::Storage("Encryption failed".to_string()))?;
+        Ok(EncryptedFile {
+            version: 1,
+            kdf: KDF.to_string(),
+            kdfparams: self.params,
+            salt: hex::encode(&self.salt),
+            cipher: CIPHER.to_string(),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        })
+    }
+
+    /// Whether a file was encrypted under this key's salt (so no key derivation is needed)
+    pub fn matches(&self, file: &EncryptedFile) -> bool {
+        decode(&file.salt).is_ok_and(|salt| salt == self.salt)
+    }
+
+    /// Decrypt a file encrypted under this key (i.e. with the same passphrase and salt)
+    pub fn decrypt(&self, file: &EncryptedFile) -> Result<Zeroizing<Vec<u8>>, CliError> {
+        let nonce = decode(&file.nonce)?;
+        if nonce.len() != NONCE_LEN {
+            return Err(CliError::Storage("Invalid encryption nonce".to_string()));
+        }
+        XChaCha20Poly1305::new(&self.key.into())
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                decode(&file.ciphertext)?.as_slice(),
+            )
+            .map(Zeroizing::new)
+            .map_err(|_| CliError::InvalidInput("Wrong wallet passphrase".to_string()))
+    }
+}
+
+impl PartialEq for Cipher {
+    fn eq(&self, other: &Self) -> bool {
+        self.salt == other.salt && self.key == other.key
+    }
+}
+
+impl Drop for Cipher {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, CliError> {
+    hex::decode(value).map_err(|e| CliError::Storage(format!("Invalid encrypted file: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::{temp_wallet, ticket, PUBKEY};
+    use crate::store::TicketStore;
+
+    fn value(passphrase: &str) -> Passphrase {
+        Passphrase::Value(Zeroizing::new(passphrase.to_string()))
+    }
+
+    fn wrong_passphrase(result: Result<impl Sized, CliError>) -> bool {
+        matches!(result, Err(CliError::InvalidInput(m)) if m == "Wrong wallet passphrase")
+    }
+
+    #[test]
+    fn sealed_files_open_with_the_passphrase_only() {
+        let cipher = Cipher::new("correct horse").unwrap();
+        let contents = serde_json::to_string(&cipher.encrypt(b"tickets").unwrap()).unwrap();
+
+        let file = EncryptedFile::parse(&contents).unwrap();
+        assert!(cipher.matches(&file));
+        assert_eq!(cipher.decrypt(&file).unwrap().as_slice(), b"tickets");
+        let (unlocked, plaintext) = file.unlock("correct horse").unwrap();
+        assert!(unlocked == cipher);
+        assert_eq!(plaintext.as_slice(), b"tickets");
+
+        assert!(wrong_passphrase(file.unlock("battery staple")));
+        let other = Cipher::new("battery staple").unwrap();
+        assert!(!other.matches(&file));
+        assert!(wrong_passphrase(other.decrypt(&file)));
+    }
+
+    #[test]
+    fn plaintext_contents_are_not_parsed_as_encrypted() {
+        assert!(EncryptedFile::parse(r#"{"batches": []}"#).is_none());
+        assert!(EncryptedFile::parse(r#"[{"id": "0x01"}]"#).is_none());
+        assert!(EncryptedFile::parse("not json").is_none());
+    }
+
+    #[test]
+    fn wallet_encrypt_and_decrypt_round_trip_through_the_store() {
+        let path = temp_wallet();
+        let mut store = TicketStore::open_at(&path, &Passphrase::None).unwrap();
+        let batch = store.add_batch(1, PUBKEY, vec![ticket(1)]).unwrap();
+
+        // wallet encrypt
+        store.set_passphrase(Some("correct horse")).unwrap();
+        store.save().unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(EncryptedFile::parse(&contents).is_some());
+        assert!(!contents.contains(&batch));
+
+        assert!(matches!(
+            TicketStore::open_at(&path, &Passphrase::None),
+            Err(CliError::InvalidInput(_))
+        ));
+        assert!(wrong_passphrase(TicketStore::open_at(
+            &path,
+            &value("battery staple")
+        )));
+        let mut store = TicketStore::open_at(&path, &value("correct horse")).unwrap();
+        assert!(store.is_encrypted());
+        assert!(store.batch(&batch).unwrap().contains(&ticket(1).id));
+
+        // wallet decrypt
+        store.set_passphrase(None).unwrap();
+        store.save().unwrap();
+        assert!(EncryptedFile::parse(&fs::read_to_string(&path).unwrap()).is_none());
+        let store = TicketStore::open_at(&path, &Passphrase::None).unwrap();
+        assert!(!store.is_encrypted());
+        assert!(store.batch(&batch).is_ok());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+use eth_stealth_gas_tickets::VerifierError;
+use std::fmt;
+
+/// Error returned by every command, grouped by category so each maps to a stable exit code
+#[derive(Debug)]
+pub enum CliError {
+    /// Bad command line arguments or input files (invalid pubkey, address, JSON, ...)
+    InvalidInput(String),
+    /// An output file that would be overwritten already exists
+    FileConflict(String),
+    /// The local wallet or another file under ~/.stealthereum could not be read or written
+    Storage(String),
+    /// The config file or chain registry is invalid
+    Config(String),
+    /// No chain registry entry for the requested chain ID
+    UnsupportedChain(String),
+    /// The JSON-RPC node returned an error or could not be reached
+    Rpc(String),
+    /// A transaction was mined but reverted
+    TransactionFailed(String),
+    /// The coordinator could not be reached or rejected the request
+    Coordinator(String),
+    /// A ticket signature did not verify or could not be finalized
+    Verification(String),
+    /// Gave up waiting for an onchain event
+    Timeout(String),
+    /// The paying account cannot cover the value and gas of a transaction
+    InsufficientFunds(String),
+}
+
+impl CliError {
+    /// Process exit code for this error (documented in the README, never renumbered)
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            CliError::InvalidInput(_) => 2,
+            CliError::FileConflict(_) => 3,
+            CliError::Storage(_) => 4,
+            CliError::Config(_) => 5,
+            CliError::UnsupportedChain(_) => 6,
+            CliError::Rpc(_) => 7,
+            CliError::TransactionFailed(_) => 8,
+            CliError::Coordinator(_) => 9,
+            CliError::Verification(_) => 10,
+            CliError::Timeout(_) => 11,
+            CliError::InsufficientFunds(_) => 12,
+        }
+    }
+
+    /// Stable machine readable name of the error category
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CliError::InvalidInput(_) => "invalid-input",
+            CliError::FileConflict(_) => "file-conflict",
+            CliError::Storage(_) => "storage",
+            CliError::Config(_) => "config",
+            CliError::UnsupportedChain(_) => "unsupported-chain",
+            CliError::Rpc(_) => "rpc",
+            CliError::TransactionFailed(_) => "transaction-failed",
+            CliError::Coordinator(_) => "coordinator",
+            CliError::Verification(_) => "verification",
+            CliError::Timeout(_) => "timeout",
+            CliError::InsufficientFunds(_) => "insufficient-funds",
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::InvalidInput(e)
+            | CliError::FileConflict(e)
+            | CliError::Storage(e)
+            | CliError::Config(e)
+            | CliError::UnsupportedChain(e)
+            | CliError::Rpc(e)
+            | CliError::TransactionFailed(e)
+            | CliError::Coordinator(e)
+            | CliError::Verification(e)
+            | CliError::Timeout(e)
+            | CliError::InsufficientFunds(e) => f.write_str(e),
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+impl From<std::io::Error> for CliError {
+    fn from(e: std::io::Error) -> Self {
+        CliError::Storage(format!("I/O error: {}", e))
+    }
+}
+
+impl From<serde_json::Error> for CliError {
+    fn from(e: serde_json::Error) -> Self {
+        CliError::InvalidInput(format!("Invalid JSON: {}", e))
+    }
+}
+
+impl From<toml::de::Error> for CliError {
+    fn from(e: toml::de::Error) -> Self {
+        CliError::Config(format!("Invalid TOML: {}", e))
+    }
+}
+
+impl From<toml::ser::Error> for CliError {
+    fn from(e: toml::ser::Error) -> Self {
+        CliError::Config(format!("Failed to serialize TOML: {}", e))
+    }
+}
+
+impl From<VerifierError> for CliError {
+    fn from(e: VerifierError) -> Self {
+        CliError::Verification(e.to_string())
+    }
+}
+
+impl From<alloy::transports::TransportError> for CliError {
+    fn from(e: alloy::transports::TransportError) -> Self {
+        CliError::Rpc(format!("RPC request failed: {}", e))
+    }
+}
+
+impl From<alloy::contract::Error> for CliError {
+    fn from(e: alloy::contract::Error) -> Self {
+        CliError::Rpc(format!("Contract call failed: {}", e))
+    }
+}
+
+impl From<alloy::providers::PendingTransactionError> for CliError {
+    fn from(e: alloy::providers::PendingTransactionError) -> Self {
+        CliError::Rpc(format!("Failed to watch transaction: {}", e))
+    }
+}
+
+impl From<reqwest::Error> for CliError {
+    fn from(e: reqwest::Error) -> Self {
+        CliError::Coordinator(format!("Coordinator request failed: {}", e))
+    }
+}
@@ -0,0 +1,18 @@
+//! Client side of the StealthGasStation blind gas tickets protocol.
+//!
+//! [`StealthGasClient`] generates, buys, finalizes, verifies and redeems tickets against one
+//! deployment while tracking them in the local wallet; the `stealth-gas` binary is a thin CLI
+//! over it.
+
+pub mod chains;
+pub mod client;
+pub mod commands;
+pub mod config;
+pub mod encryption;
+pub mod error;
+pub mod output;
+pub mod signer;
+pub mod store;
+
+pub use client::{ClientConfig, Progress, StealthGasClient};
+pub use error::CliError;
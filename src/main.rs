@@ -1,11 +1,40 @@
-mod commands;
-
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+use stealth_gas_cli::commands;
+use stealth_gas_cli::commands::utils::{parse_amount, parse_fee_per_gas};
+use stealth_gas_cli::config;
+use stealth_gas_cli::encryption::Passphrase;
+use stealth_gas_cli::output::{self, emit, Format};
+use stealth_gas_cli::signer::SignerOptions;
+use stealth_gas_cli::CliError;
+use zeroize::Zeroizing;
 
 #[derive(Parser)]
 #[command(name = "stealth-gas")]
 #[command(about = "CLI for managing Ethereum blind gas tickets on client side", long_about = None)]
 struct Cli {
+    #[arg(
+        long = "profile",
+        global = true,
+        help = "Named profile from ~/.stealthereum/config.toml providing default options"
+    )]
+    profile: Option<String>,
+    #[arg(
+        long = "format",
+        global = true,
+        value_enum,
+        default_value_t = Format::Text,
+        help = "Output format (json prints a single JSON document on stdout)"
+    )]
+    format: Format,
+    #[arg(
+        long = "passphrase-file",
+        global = true,
+        help = "Read the wallet passphrase from this file instead of prompting (or set STEALTH_GAS_PASSPHRASE)"
+    )]
+    passphrase_file: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -21,7 +50,7 @@ enum Commands {
         #[arg(
             short = 'o',
             long = "output",
-            help = "Output JSON file path of Vec<UnsignedTicket> type"
+            help = "Also export the tickets to a JSON file of Vec<UnsignedTicket> type"
         )]
         output: Option<String>,
         #[arg(short = 'c', long = "chain-id", help = "Chain ID")]
@@ -34,13 +63,19 @@ enum Commands {
         #[arg(
             short = 'i',
             long = "input",
-            help = "Input JSON file path of Vec<UnsignedTicket> type (unsigned tickets)"
+            help = "Input JSON file path of Vec<UnsignedTicket> type (imported into the local wallet)"
         )]
         input: Option<String>,
+        #[arg(
+            short = 'b',
+            long = "batch",
+            help = "Ticket batch ID in the local wallet (defaults to the most recent bought batch)"
+        )]
+        batch: Option<String>,
         #[arg(
             short = 'o',
             long = "output",
-            help = "Output JSON file path of Vec<SignedTicket> type (redeemable gas tickets)"
+            help = "Also export the redeemable gas tickets to a JSON file of Vec<SignedTicket> type"
         )]
         output: Option<String>,
         #[arg(
@@ -55,10 +90,32 @@ enum Commands {
             help = "StealthGasStation contract address"
         )]
         gas_station_address: Option<String>,
-        #[arg(short = 's', long = "start-block", help = "Start block number")]
-        start_block: u64,
+        #[arg(
+            short = 's',
+            long = "start-block",
+            help = "Start block number (defaults to resuming after the last scanned block, or the block the tickets were bought in)"
+        )]
+        start_block: Option<u64>,
         #[arg(short = 'c', long = "chain-id", help = "Chain ID")]
         chain_id: Option<u64>,
+        #[arg(
+            short = 'w',
+            long = "watch",
+            help = "Keep polling until every purchased ticket is signed by the coordinator"
+        )]
+        watch: bool,
+        #[arg(
+            long = "poll-interval",
+            default_value_t = 15,
+            help = "Seconds between polls in watch mode"
+        )]
+        poll_interval: u64,
+        #[arg(
+            long = "timeout",
+            default_value_t = 3600,
+            help = "Seconds to wait for signatures in watch mode before giving up"
+        )]
+        timeout: u64,
     },
     /// Verify signatures of finalized gas tickets
     Verify {
@@ -69,7 +126,13 @@ enum Commands {
             long = "input",
             help = "Input JSON file path of Vec<SignedTicket> type (redeemable gas tickets)"
         )]
-        input: String,
+        input: Option<String>,
+        #[arg(
+            short = 'b',
+            long = "batch",
+            help = "Ticket batch ID in the local wallet (defaults to the most recent batch)"
+        )]
+        batch: Option<String>,
         #[arg(short = 'c', long = "chain-id", help = "Chain ID")]
         chain_id: Option<u64>,
     },
@@ -81,11 +144,20 @@ enum Commands {
             help = "RPC URL (for a supported chain id)"
         )]
         rpc: Option<String>,
+        #[arg(
+            short = 'g',
+            long = "gas-station-address",
+            help = "StealthGasStation contract address"
+        )]
+        gas_station_address: Option<String>,
         #[arg(short = 'c', long = "chain-id", help = "Chain ID")]
         chain_id: Option<u64>,
     },
     /// Buy gas tickets onchain (requires funds for tx)
+    #[command(args_conflicts_with_subcommands = true)]
     Buy {
+        #[command(subcommand)]
+        step: Option<Box<BuyStep>>,
         #[arg(
             short = 'r',
             long = "rpc-url",
@@ -101,23 +173,51 @@ enum Commands {
         #[arg(
             short = 'i',
             long = "input",
-            help = "Input JSON file path of Vec<UnsignedTicket> type"
+            help = "Input JSON file path of Vec<UnsignedTicket> type (imported into the local wallet; repeat to buy several files in one transaction)"
         )]
-        input: Option<String>,
+        input: Vec<String>,
         #[arg(
-            short = 'k',
-            long = "key",
-            help = "Private key for transaction signing"
+            short = 'b',
+            long = "batch",
+            help = "Ticket batch ID in the local wallet (defaults to the most recent batch; repeat to buy several batches in one transaction)"
         )]
-        key: Option<String>,
+        batch: Vec<String>,
         #[arg(
-            short = 'a',
-            long = "account",
-            help = "Path to keystore file for transaction signing"
+            long = "all",
+            conflicts_with_all = ["input", "batch"],
+            help = "Buy the tickets left in every batch of the local wallet in one transaction"
         )]
-        account: Option<String>,
+        all: bool,
+        #[command(flatten)]
+        signer: SignerArgs,
         #[arg(short = 'c', long = "chain-id", help = "Chain ID")]
         chain_id: Option<u64>,
+        #[arg(
+            long = "dry-run",
+            visible_alias = "quote",
+            help = "Simulate the purchase and print the all-in cost without sending a transaction"
+        )]
+        dry_run: bool,
+        #[arg(
+            long = "confirmations",
+            default_value_t = 1,
+            value_parser = clap::value_parser!(u64).range(1..),
+            help = "Number of blocks to wait for (counting the inclusion block) before reporting the purchase"
+        )]
+        confirmations: u64,
+        #[arg(
+            long = "timeout",
+            help = "Seconds to wait for the confirmations before giving up (the purchase stays pending)"
+        )]
+        timeout: Option<u64>,
+        #[arg(
+            long = "from",
+            requires = "dry_run",
+            help = "Address to simulate the purchase from in dry-run mode (instead of --key / --account)"
+        )]
+        from: Option<String>,
+        #[command(flatten)]
+        fees: FeeArgs,
     },
     /// Redeem signed tickets through coordinator
     Redeem {
@@ -129,6 +229,12 @@ enum Commands {
             help = "Input JSON file path of Vec<SignedTicket> type"
         )]
         input: Option<String>,
+        #[arg(
+            short = 'b',
+            long = "batch",
+            help = "Ticket batch ID in the local wallet (defaults to the most recent batch)"
+        )]
+        batch: Option<String>,
         #[arg(
             short = 's',
             long = "spends",
@@ -137,59 +243,871 @@ enum Commands {
         spends: String,
         #[arg(short = 'c', long = "chain-id", help = "Chain ID")]
         chain_id: Option<u64>,
+        #[arg(
+            long = "retry",
+            help = "Resend tickets left redemption-submitted by a redemption whose outcome is unknown (pass the same spends)"
+        )]
+        retry: bool,
+    },
+    /// Summarize the ticket batches held in the local wallet
+    Status {
+        #[arg(
+            short = 'r',
+            long = "rpc-url",
+            requires = "chain_id",
+            help = "RPC URL used to fetch the ticket cost of the --chain-id chain"
+        )]
+        rpc: Option<String>,
+        #[arg(
+            short = 'g',
+            long = "gas-station-address",
+            requires = "chain_id",
+            help = "StealthGasStation contract address on the --chain-id chain"
+        )]
+        contract_address: Option<String>,
+        #[arg(
+            short = 'c',
+            long = "chain-id",
+            help = "Only show batches for this chain ID"
+        )]
+        chain_id: Option<u64>,
+    },
+    /// Show or edit profiles in ~/.stealthereum/config.toml
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
     },
+    /// Encrypt or decrypt the local wallet
+    Wallet {
+        #[command(subcommand)]
+        action: WalletAction,
+    },
+    /// Create, import and list keystore accounts in ~/.stealthereum/keystores
+    Account {
+        #[command(subcommand)]
+        action: AccountAction,
+    },
+    /// Speed up or cancel a stuck buy transaction
+    Tx {
+        #[command(subcommand)]
+        action: TxAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Print the config file (or only the profile selected with --profile)
+    Show,
+    /// Set a key in the selected profile (or the top-level default_profile)
+    Set { key: String, value: String },
+    /// Remove a key from the selected profile (or the top-level default_profile)
+    Unset { key: String },
+}
+
+#[derive(Subcommand)]
+enum WalletAction {
+    /// Encrypt the wallet and ticket exports with a passphrase (or change the passphrase)
+    Encrypt {
+        #[arg(
+            long = "new-passphrase-file",
+            help = "Read the new passphrase from this file instead of prompting (or set STEALTH_GAS_NEW_PASSPHRASE)"
+        )]
+        new_passphrase_file: Option<PathBuf>,
+    },
+    /// Store the wallet in plaintext again
+    Decrypt,
+}
+
+/// Purchases signed on an offline machine or executed by a smart contract wallet
+#[derive(Subcommand)]
+enum BuyStep {
+    /// Build the unsigned purchase transaction (online, no key needed)
+    Build {
+        #[arg(
+            short = 'r',
+            long = "rpc-url",
+            help = "RPC URL (for a supported chain id)"
+        )]
+        rpc: Option<String>,
+        #[arg(
+            short = 'g',
+            long = "gas-station-address",
+            help = "StealthGasStation contract address"
+        )]
+        contract_address: Option<String>,
+        #[arg(
+            short = 'i',
+            long = "input",
+            help = "Input JSON file path of Vec<UnsignedTicket> type (imported into the local wallet)"
+        )]
+        input: Option<String>,
+        #[arg(
+            short = 'b',
+            long = "batch",
+            help = "Ticket batch ID in the local wallet (defaults to the most recent batch)"
+        )]
+        batch: Option<String>,
+        #[arg(
+            long = "from",
+            help = "Address of the account that will sign the purchase"
+        )]
+        from: String,
+        #[arg(short = 'c', long = "chain-id", help = "Chain ID")]
+        chain_id: Option<u64>,
+        #[arg(
+            short = 'o',
+            long = "output",
+            help = "Output JSON file path of the unsigned transaction"
+        )]
+        output: String,
+        #[command(flatten)]
+        fees: FeeArgs,
+    },
+    /// Print the purchase call for a Safe or other smart contract wallet and mark the tickets
+    /// pending
+    Calldata {
+        #[arg(
+            short = 'r',
+            long = "rpc-url",
+            help = "RPC URL (for a supported chain id)"
+        )]
+        rpc: Option<String>,
+        #[arg(
+            short = 'g',
+            long = "gas-station-address",
+            help = "StealthGasStation contract address"
+        )]
+        contract_address: Option<String>,
+        #[arg(
+            short = 'i',
+            long = "input",
+            help = "Input JSON file path of Vec<UnsignedTicket> type (imported into the local wallet)"
+        )]
+        input: Option<String>,
+        #[arg(
+            short = 'b',
+            long = "batch",
+            help = "Ticket batch ID in the local wallet (defaults to the most recent batch with tickets to buy or exported)"
+        )]
+        batch: Option<String>,
+        #[arg(
+            long = "safe",
+            help = "Address of the Safe (or other wallet) executing the call, to simulate it from"
+        )]
+        safe: Option<String>,
+        #[arg(short = 'c', long = "chain-id", help = "Chain ID")]
+        chain_id: Option<u64>,
+        #[arg(
+            long = "safe-json",
+            help = "Also write the call as a Safe Transaction Builder batch to this file"
+        )]
+        safe_json: Option<String>,
+    },
+    /// Make the tickets of an abandoned `buy calldata` proposal purchasable again (only once
+    /// the wallet can no longer execute it)
+    Release {
+        #[arg(
+            short = 'b',
+            long = "batch",
+            help = "Ticket batch ID in the local wallet (defaults to the most recent exported batch)"
+        )]
+        batch: Option<String>,
+        #[arg(short = 'c', long = "chain-id", help = "Chain ID")]
+        chain_id: Option<u64>,
+    },
+    /// Sign a transaction written by `buy build` (offline, no RPC needed)
+    Sign {
+        #[arg(
+            short = 'i',
+            long = "input",
+            help = "Unsigned transaction JSON file written by `buy build`"
+        )]
+        input: String,
+        #[arg(
+            short = 'o',
+            long = "output",
+            help = "Output JSON file path of the signed transaction"
+        )]
+        output: String,
+        #[command(flatten)]
+        signer: SignerArgs,
+    },
+    /// Submit a transaction signed with `buy sign` and wait for it
+    Broadcast {
+        #[arg(
+            short = 'i',
+            long = "input",
+            help = "Signed transaction JSON file written by `buy sign`"
+        )]
+        input: String,
+        #[arg(
+            short = 'r',
+            long = "rpc-url",
+            help = "RPC URL (for a supported chain id)"
+        )]
+        rpc: Option<String>,
+        #[arg(
+            short = 'g',
+            long = "gas-station-address",
+            help = "StealthGasStation contract address the transaction must call"
+        )]
+        contract_address: Option<String>,
+        #[arg(
+            short = 'c',
+            long = "chain-id",
+            help = "Chain ID (defaults to, and must match, the chain the transaction is signed for)"
+        )]
+        chain_id: Option<u64>,
+        #[arg(
+            long = "confirmations",
+            default_value_t = 1,
+            value_parser = clap::value_parser!(u64).range(1..),
+            help = "Number of blocks to wait for (counting the inclusion block) before reporting the purchase"
+        )]
+        confirmations: u64,
+        #[arg(
+            long = "timeout",
+            help = "Seconds to wait for the confirmations before giving up (the purchase stays pending)"
+        )]
+        timeout: Option<u64>,
+    },
+}
+
+/// Key that signs the transaction
+#[derive(clap::Args)]
+struct SignerArgs {
+    #[arg(
+        short = 'k',
+        long = "key",
+        help = "Private key for transaction signing"
+    )]
+    key: Option<String>,
+    #[arg(
+        long = "key-env",
+        value_name = "VAR",
+        help = "Environment variable holding the private key for transaction signing"
+    )]
+    key_env: Option<String>,
+    #[arg(
+        long = "key-file",
+        value_name = "PATH",
+        help = "File holding the private key for transaction signing (must not be world-readable)"
+    )]
+    key_file: Option<PathBuf>,
+    #[arg(
+        long = "key-stdin",
+        help = "Read the private key for transaction signing from the first line of stdin"
+    )]
+    key_stdin: bool,
+    #[arg(
+        short = 'a',
+        long = "account",
+        help = "Keystore file path or account alias (see `stealth-gas account list`) for transaction signing"
+    )]
+    account: Option<String>,
+    #[arg(
+        long = "mnemonic",
+        help = "Derive the signing key from a BIP-39 mnemonic (prompted for, never passed as an argument)"
+    )]
+    mnemonic: bool,
+    #[arg(
+        long = "mnemonic-index",
+        requires = "mnemonic",
+        conflicts_with = "derivation_path",
+        help = "Account index of the mnemonic key, derived at m/44'/60'/0'/0/<index> [default: 0]"
+    )]
+    mnemonic_index: Option<u32>,
+    #[arg(
+        long = "derivation-path",
+        requires = "mnemonic",
+        help = "Full derivation path of the mnemonic key (e.g. m/44'/60'/1'/0/0)"
+    )]
+    derivation_path: Option<String>,
+    #[arg(
+        long = "remote-signer",
+        help = "URL of an external JSON-RPC signer to sign with eth_signTransaction (e.g. Clef or web3signer)"
+    )]
+    remote_signer: Option<String>,
+    #[arg(
+        long = "remote-signer-address",
+        requires = "remote_signer",
+        help = "Account of the remote signer to use (defaults to its first account)"
+    )]
+    remote_signer_address: Option<String>,
+}
+
+impl SignerArgs {
+    /// Signer options, falling back to the profile's keystore when no key source is given
+    fn options(self, profile_account: Option<String>) -> SignerOptions {
+        let other_source = self.key.is_some()
+            || self.key_env.is_some()
+            || self.key_file.is_some()
+            || self.key_stdin
+            || self.mnemonic
+            || self.remote_signer.is_some();
+        let account = if other_source {
+            self.account
+        } else {
+            self.account.or(profile_account)
+        };
+        SignerOptions {
+            private_key: self.key.map(Zeroizing::new),
+            key_env: self.key_env,
+            key_file: self.key_file,
+            key_stdin: self.key_stdin,
+            account,
+            mnemonic: self.mnemonic,
+            mnemonic_index: self.mnemonic_index,
+            derivation_path: self.derivation_path,
+            remote_signer: self.remote_signer,
+            remote_signer_address: self.remote_signer_address,
+        }
+    }
+}
+
+/// Fee settings of a purchase transaction
+#[derive(clap::Args)]
+struct FeeArgs {
+    #[arg(
+        long = "max-fee-per-gas",
+        help = "EIP-1559 max fee per gas, in wei or with a gwei/eth suffix (e.g. 30gwei; estimated if unset)"
+    )]
+    max_fee_per_gas: Option<String>,
+    #[arg(
+        long = "max-priority-fee",
+        help = "EIP-1559 max priority fee per gas, in wei or with a gwei/eth suffix (estimated if unset)"
+    )]
+    max_priority_fee: Option<String>,
+    #[arg(long = "gas-limit", help = "Gas limit (estimated if unset)")]
+    gas_limit: Option<u64>,
+    #[arg(
+        long = "nonce",
+        help = "Transaction nonce (the next nonce of the sender if unset)"
+    )]
+    nonce: Option<u64>,
+    #[arg(
+        long = "max-total-cost",
+        help = "Abort if tickets, shipping and the maximum gas fee could cost more than this (wei or with a gwei/eth suffix)"
+    )]
+    max_total_cost: Option<String>,
+}
+
+impl FeeArgs {
+    fn options(
+        self,
+        confirmations: u64,
+        timeout: Option<u64>,
+    ) -> Result<commands::buy::BuyOptions, CliError> {
+        Ok(commands::buy::BuyOptions {
+            confirmations,
+            timeout: timeout.map(Duration::from_secs),
+            max_fee_per_gas: self
+                .max_fee_per_gas
+                .as_deref()
+                .map(parse_fee_per_gas)
+                .transpose()?,
+            max_priority_fee_per_gas: self
+                .max_priority_fee
+                .as_deref()
+                .map(parse_fee_per_gas)
+                .transpose()?,
+            gas_limit: self.gas_limit,
+            nonce: self.nonce,
+            max_total_cost: self
+                .max_total_cost
+                .as_deref()
+                .map(parse_amount)
+                .transpose()?,
+        })
+    }
+}
+
+#[derive(Subcommand)]
+enum AccountAction {
+    /// Create a keystore with a new random key
+    New { alias: String },
+    /// Encrypt an existing private key (prompted for by default) into a keystore
+    Import {
+        alias: String,
+        #[arg(
+            long = "key-env",
+            value_name = "VAR",
+            help = "Environment variable holding the private key"
+        )]
+        key_env: Option<String>,
+        #[arg(
+            long = "key-file",
+            value_name = "PATH",
+            help = "File holding the private key (must not be world-readable)"
+        )]
+        key_file: Option<PathBuf>,
+        #[arg(
+            long = "key-stdin",
+            help = "Read the private key from the first line of stdin"
+        )]
+        key_stdin: bool,
+        #[arg(
+            long = "mnemonic",
+            help = "Derive the key from a BIP-39 mnemonic (prompted for)"
+        )]
+        mnemonic: bool,
+        #[arg(
+            long = "mnemonic-index",
+            requires = "mnemonic",
+            conflicts_with = "derivation_path",
+            help = "Account index of the mnemonic key, derived at m/44'/60'/0'/0/<index> [default: 0]"
+        )]
+        mnemonic_index: Option<u32>,
+        #[arg(
+            long = "derivation-path",
+            requires = "mnemonic",
+            help = "Full derivation path of the mnemonic key (e.g. m/44'/60'/1'/0/0)"
+        )]
+        derivation_path: Option<String>,
+    },
+    /// List the accounts and their addresses
+    List,
+    /// Print the address of an account alias or keystore file
+    Address { account: String },
+}
+
+#[derive(Subcommand)]
+enum TxAction {
+    /// Rebroadcast a pending buy transaction with higher fees
+    Speedup {
+        #[command(flatten)]
+        args: ReplaceArgs,
+    },
+    /// Replace a pending buy transaction with a zero-value self-transfer at the same nonce
+    Cancel {
+        #[command(flatten)]
+        args: ReplaceArgs,
+    },
+}
+
+#[derive(clap::Args)]
+struct ReplaceArgs {
+    #[arg(help = "Hash of the pending buy transaction")]
+    hash: String,
+    #[arg(
+        short = 'r',
+        long = "rpc-url",
+        help = "RPC URL (for a supported chain id)"
+    )]
+    rpc: Option<String>,
+    #[command(flatten)]
+    signer: SignerArgs,
+    #[arg(short = 'c', long = "chain-id", help = "Chain ID")]
+    chain_id: Option<u64>,
+    #[arg(
+        long = "bump",
+        default_value_t = 15,
+        help = "Percent to raise the fees of the original transaction by (at least 10)"
+    )]
+    bump: u64,
+    #[arg(
+        long = "max-fee-per-gas",
+        help = "EIP-1559 max fee per gas of the replacement, in wei or with a gwei/eth suffix"
+    )]
+    max_fee_per_gas: Option<String>,
+    #[arg(
+        long = "max-priority-fee",
+        help = "EIP-1559 max priority fee per gas of the replacement, in wei or with a gwei/eth suffix"
+    )]
+    max_priority_fee: Option<String>,
+}
+
+impl Commands {
+    /// Chain ID passed on the command line, if the command takes one
+    fn chain_id(&self) -> Option<u64> {
+        match self {
+            Commands::New { chain_id, .. }
+            | Commands::Finalize { chain_id, .. }
+            | Commands::Verify { chain_id, .. }
+            | Commands::Params { chain_id, .. }
+            | Commands::Redeem { chain_id, .. }
+            | Commands::Status { chain_id, .. } => *chain_id,
+            Commands::Buy {
+                step: Some(step), ..
+            } => match step.as_ref() {
+                BuyStep::Build { chain_id, .. }
+                | BuyStep::Calldata { chain_id, .. }
+                | BuyStep::Release { chain_id, .. }
+                | BuyStep::Broadcast { chain_id, .. } => *chain_id,
+                BuyStep::Sign { .. } => None,
+            },
+            Commands::Buy { chain_id, .. } => *chain_id,
+            Commands::Tx {
+                action: TxAction::Speedup { args } | TxAction::Cancel { args },
+            } => args.chain_id,
+            Commands::Config { .. } | Commands::Wallet { .. } | Commands::Account { .. } => None,
+        }
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> ExitCode {
     let cli = Cli::parse();
+    let format = cli.format;
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            output::emit_error(format, &e);
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
 
+async fn run(cli: Cli) -> Result<(), CliError> {
+    // Commands fall back to the selected profile for any option not passed on the command line
+    let profile = match &cli.command {
+        Commands::Config { .. } | Commands::Wallet { .. } | Commands::Account { .. } => {
+            config::Profile::default()
+        }
+        _ => config::Config::load()?
+            .profile(cli.profile.as_deref())?
+            .for_chain(cli.command.chain_id()),
+    };
+
+    let format = cli.format;
+    let passphrase = Passphrase::from_env_or_file(cli.passphrase_file);
     match cli.command {
         Commands::New {
             pubkey,
             num,
             output,
             chain_id,
-        } => Ok(commands::new::run(pubkey, num, output, chain_id)),
+        } => emit(
+            format,
+            &commands::new::run(
+                pubkey.or(profile.coordinator_pubkey),
+                num,
+                output,
+                chain_id.or(profile.chain_id),
+                passphrase,
+            )?,
+        ),
         Commands::Finalize {
             pubkey,
             input,
+            batch,
             output,
             rpc,
             gas_station_address,
             start_block,
             chain_id,
+            watch,
+            poll_interval,
+            timeout,
         } => {
-            commands::finalize::run(
-                pubkey,
+            let report = commands::finalize::run(
+                pubkey.or(profile.coordinator_pubkey),
                 input,
+                batch,
                 output,
-                rpc,
-                gas_station_address,
+                rpc.or(profile.rpc_url),
+                gas_station_address.or(profile.gas_station_address),
                 start_block,
-                chain_id,
+                chain_id.or(profile.chain_id),
+                watch,
+                poll_interval,
+                timeout,
+                passphrase,
             )
-            .await
+            .await?;
+            emit(format, &report)
         }
         Commands::Verify {
             pubkey,
             input,
+            batch,
             chain_id,
-        } => Ok(commands::verify::run(pubkey, input, chain_id)),
-        Commands::Params { rpc, chain_id } => commands::params::run(rpc, chain_id).await,
+        } => emit(
+            format,
+            &commands::verify::run(
+                pubkey.or(profile.coordinator_pubkey),
+                input,
+                batch,
+                chain_id.or(profile.chain_id),
+                passphrase,
+            )?,
+        ),
+        Commands::Params {
+            rpc,
+            gas_station_address,
+            chain_id,
+        } => {
+            let report = commands::params::run(
+                rpc.or(profile.rpc_url),
+                gas_station_address.or(profile.gas_station_address),
+                chain_id.or(profile.chain_id),
+            )
+            .await?;
+            emit(format, &report)
+        }
         Commands::Buy {
+            step: Some(step), ..
+        } => run_buy_step(*step, profile, passphrase, format).await,
+        Commands::Buy {
+            step: None,
             rpc,
             contract_address,
             input,
-            key,
-            account,
+            batch,
+            all,
+            signer,
             chain_id,
-        } => commands::buy::run(rpc, contract_address, input, key, account, chain_id).await,
+            dry_run,
+            confirmations,
+            timeout,
+            from,
+            fees,
+        } => {
+            // A --from address on the command line takes precedence over the profile's keystore
+            let signer = signer.options(if from.is_some() {
+                None
+            } else {
+                profile.account
+            });
+            let options = fees.options(confirmations, timeout)?;
+            if dry_run {
+                let report = commands::buy::quote(
+                    rpc.or(profile.rpc_url),
+                    contract_address.or(profile.gas_station_address),
+                    input,
+                    batch,
+                    all,
+                    signer,
+                    from,
+                    chain_id.or(profile.chain_id),
+                    options,
+                    passphrase,
+                )
+                .await?;
+                return emit(format, &report);
+            }
+            let report = commands::buy::run(
+                rpc.or(profile.rpc_url),
+                contract_address.or(profile.gas_station_address),
+                input,
+                batch,
+                all,
+                signer,
+                chain_id.or(profile.chain_id),
+                options,
+                passphrase,
+            )
+            .await?;
+            emit(format, &report)
+        }
         Commands::Redeem {
             url,
             input,
+            batch,
             spends,
             chain_id,
-        } => commands::redeem::run(url, input, spends, chain_id).await,
+            retry,
+        } => {
+            let report = commands::redeem::run(
+                url.or(profile.coordinator_url),
+                input,
+                batch,
+                spends,
+                retry,
+                chain_id.or(profile.chain_id),
+                passphrase,
+            )
+            .await?;
+            emit(format, &report)
+        }
+        Commands::Status {
+            rpc,
+            contract_address,
+            chain_id,
+        } => {
+            let report = commands::status::run(
+                rpc.or(profile.rpc_url),
+                contract_address.or(profile.gas_station_address),
+                // A profile's chain scopes its settings but does not hide the other chains
+                chain_id,
+                chain_id.or(profile.chain_id),
+                passphrase,
+            )
+            .await?;
+            emit(format, &report)
+        }
+        Commands::Config { action } => match action {
+            ConfigAction::Show => emit(format, &commands::config::show(cli.profile)?),
+            ConfigAction::Set { key, value } => {
+                emit(format, &commands::config::set(cli.profile, key, value)?)
+            }
+            ConfigAction::Unset { key } => {
+                emit(format, &commands::config::unset(cli.profile, key)?)
+            }
+        },
+        Commands::Wallet { action } => match action {
+            WalletAction::Encrypt {
+                new_passphrase_file,
+            } => emit(
+                format,
+                &commands::wallet::encrypt(
+                    passphrase,
+                    Passphrase::new_from_env_or_file(new_passphrase_file),
+                )?,
+            ),
+            WalletAction::Decrypt => emit(format, &commands::wallet::decrypt(passphrase)?),
+        },
+        Commands::Account { action } => match action {
+            AccountAction::New { alias } => emit(format, &commands::account::new(alias)?),
+            AccountAction::Import {
+                alias,
+                key_env,
+                key_file,
+                key_stdin,
+                mnemonic,
+                mnemonic_index,
+                derivation_path,
+            } => {
+                let signer = SignerOptions {
+                    key_env,
+                    key_file,
+                    key_stdin,
+                    mnemonic,
+                    mnemonic_index,
+                    derivation_path,
+                    ..Default::default()
+                };
+                emit(format, &commands::account::import(alias, signer)?)
+            }
+            AccountAction::List => emit(format, &commands::account::list()?),
+            AccountAction::Address { account } => {
+                emit(format, &commands::account::address(account)?)
+            }
+        },
+        Commands::Tx { action } => {
+            let (action, args) = match action {
+                TxAction::Speedup { args } => (commands::tx::Replacement::Speedup, args),
+                TxAction::Cancel { args } => (commands::tx::Replacement::Cancel, args),
+            };
+            let options = commands::tx::ReplaceOptions {
+                bump_percent: args.bump,
+                max_fee_per_gas: args
+                    .max_fee_per_gas
+                    .as_deref()
+                    .map(parse_fee_per_gas)
+                    .transpose()?,
+                max_priority_fee_per_gas: args
+                    .max_priority_fee
+                    .as_deref()
+                    .map(parse_fee_per_gas)
+                    .transpose()?,
+            };
+            let report = commands::tx::run(
+                action,
+                args.hash,
+                args.rpc.or(profile.rpc_url),
+                args.signer.options(profile.account),
+                args.chain_id.or(profile.chain_id),
+                options,
+                passphrase,
+            )
+            .await?;
+            emit(format, &report)
+        }
+    }
+}
+
+async fn run_buy_step(
+    step: BuyStep,
+    profile: config::Profile,
+    passphrase: Passphrase,
+    format: Format,
+) -> Result<(), CliError> {
+    match step {
+        BuyStep::Build {
+            rpc,
+            contract_address,
+            input,
+            batch,
+            from,
+            chain_id,
+            output,
+            fees,
+        } => {
+            let report = commands::buy::build(
+                rpc.or(profile.rpc_url),
+                contract_address.or(profile.gas_station_address),
+                input,
+                batch,
+                from,
+                chain_id.or(profile.chain_id),
+                output,
+                fees.options(1, None)?,
+                passphrase,
+            )
+            .await?;
+            emit(format, &report)
+        }
+        BuyStep::Calldata {
+            rpc,
+            contract_address,
+            input,
+            batch,
+            safe,
+            chain_id,
+            safe_json,
+        } => {
+            let report = commands::buy::calldata(
+                rpc.or(profile.rpc_url),
+                contract_address.or(profile.gas_station_address),
+                input,
+                batch,
+                safe,
+                chain_id.or(profile.chain_id),
+                safe_json,
+                passphrase,
+            )
+            .await?;
+            emit(format, &report)
+        }
+        BuyStep::Release { batch, chain_id } => emit(
+            format,
+            &commands::buy::release(batch, chain_id.or(profile.chain_id), passphrase)?,
+        ),
+        BuyStep::Sign {
+            input,
+            output,
+            signer,
+        } => emit(
+            format,
+            &commands::buy::sign(input, output, signer.options(profile.account)).await?,
+        ),
+        BuyStep::Broadcast {
+            input,
+            rpc,
+            contract_address,
+            chain_id,
+            confirmations,
+            timeout,
+        } => {
+            let options = commands::buy::BuyOptions {
+                confirmations,
+                timeout: timeout.map(Duration::from_secs),
+                ..Default::default()
+            };
+            // The transaction's own chain decides which profile settings apply
+            let (signed, chain_id) = commands::buy::read_signed_purchase(&input, chain_id)?;
+            let profile = profile.for_chain(Some(chain_id));
+            let report = commands::buy::broadcast(
+                rpc.or(profile.rpc_url),
+                contract_address.or(profile.gas_station_address),
+                signed,
+                chain_id,
+                options,
+                passphrase,
+            )
+            .await?;
+            emit(format, &report)
+        }
     }
 }
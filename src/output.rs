@@ -0,0 +1,76 @@
+use crate::client::Progress;
+use crate::error::CliError;
+use alloy::primitives::U256;
+use clap::ValueEnum;
+use serde::{Serialize, Serializer};
+use std::fmt::Display;
+use std::sync::Arc;
+
+/// How command results are printed on stdout
+#[derive(ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// A single JSON document per invocation
+    Json,
+}
+
+/// Print a command result, as its Display text or as one pretty-printed JSON document
+pub fn emit<R: Serialize + Display>(format: Format, report: &R) -> Result<(), CliError> {
+    match format {
+        Format::Text => print!("{}", report),
+        Format::Json => println!("{}", serde_json::to_string_pretty(report)?),
+    }
+    Ok(())
+}
+
+/// Progress callback printing to stderr, so it never mixes with the report on stdout
+pub fn stderr_progress() -> Option<Progress> {
+    Some(Arc::new(|message: &str| eprintln!("{}", message)))
+}
+
+#[derive(Serialize)]
+struct ErrorDocument<'a> {
+    error: ErrorReport<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorReport<'a> {
+    kind: &'a str,
+    code: u8,
+    message: String,
+}
+
+/// Print a failed command's error (on stdout as JSON, so the output is still a single document)
+pub fn emit_error(format: Format, error: &CliError) {
+    match format {
+        Format::Text => eprintln!("Error: {}", error),
+        Format::Json => {
+            let document = ErrorDocument {
+                error: ErrorReport {
+                    kind: error.kind(),
+                    code: error.exit_code(),
+                    message: error.to_string(),
+                },
+            };
+            match serde_json::to_string_pretty(&document) {
+                Ok(json) => println!("{}", json),
+                Err(_) => eprintln!("Error: {}", error),
+            }
+        }
+    }
+}
+
+/// Serialize a wei amount as a decimal string (U256 serializes as hex by default)
+pub fn wei<S: Serializer>(value: &U256, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&value.to_string())
+}
+
+/// Serialize an optional wei amount as a decimal string or null
+pub fn opt_wei<S: Serializer>(value: &Option<U256>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => wei(value, serializer),
+        None => serializer.serialize_none(),
+    }
+}
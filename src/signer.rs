@@ -0,0 +1,433 @@
+use crate::commands::account::resolve_account;
+use crate::commands::utils::{parse_address, parse_url};
+use crate::error::CliError;
+use alloy::{
+    consensus::{SignableTransaction, TxEnvelope},
+    eips::eip2718::Decodable2718,
+    network::{EthereumWallet, TxSigner},
+    primitives::{Address, Bytes, PrimitiveSignature},
+    rpc::client::{ClientBuilder, RpcClient},
+    signers::local::PrivateKeySigner,
+    transports::http::{Client, Http},
+};
+use alloy_signer_local::{coins_bip39::English, MnemonicBuilder};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::PathBuf;
+use zeroize::Zeroizing;
+
+/// Where to load the key that signs buy and replacement transactions from
+#[derive(Clone, Default)]
+pub struct SignerOptions {
+    /// Raw private key
+    pub private_key: Option<Zeroizing<String>>,
+    /// Environment variable holding the private key
+    pub key_env: Option<String>,
+    /// File holding the private key (refused if world-readable)
+    pub key_file: Option<PathBuf>,
+    /// Read the private key from the first line of standard input
+    pub key_stdin: bool,
+    /// Path to a keystore file, or the alias of one in ~/.stealthereum/keystores (its password
+    /// is prompted for)
+    pub account: Option<String>,
+    /// Derive the key from a BIP-39 mnemonic prompted for on the terminal
+    pub mnemonic: bool,
+    /// Account index under m/44'/60'/0'/0 (0 if neither this nor a path is given)
+    pub mnemonic_index: Option<u32>,
+    /// Full derivation path, e.g. m/44'/60'/1'/0/0
+    pub derivation_path: Option<String>,
+    /// URL of an external JSON-RPC signer that signs with eth_signTransaction
+    pub remote_signer: Option<String>,
+    /// Account of the remote signer to use (its first account if unset)
+    pub remote_signer_address: Option<String>,
+}
+
+impl SignerOptions {
+    /// Number of key sources given (only one may be used)
+    fn sources(&self) -> usize {
+        [
+            self.private_key.is_some(),
+            self.key_env.is_some(),
+            self.key_file.is_some(),
+            self.key_stdin,
+            self.account.is_some(),
+            self.mnemonic,
+            self.remote_signer.is_some(),
+        ]
+        .iter()
+        .filter(|s| **s)
+        .count()
+    }
+
+    /// Whether any key source was given
+    pub fn is_set(&self) -> bool {
+        self.sources() > 0
+    }
+
+    /// Wallet signing with the selected key source, connecting to the remote signer if one is
+    /// given
+    pub async fn wallet(&self) -> Result<EthereumWallet, CliError> {
+        match &self.remote_signer {
+            Some(url) => {
+                self.check_sources()?;
+                let address = self
+                    .remote_signer_address
+                    .as_deref()
+                    .map(parse_address)
+                    .transpose()?;
+                Ok(EthereumWallet::from(
+                    RemoteSigner::connect(url, address).await?,
+                ))
+            }
+            None => Ok(EthereumWallet::from(self.load()?)),
+        }
+    }
+
+    /// Load a local signing key, prompting for a keystore password or mnemonic as needed.
+    /// Key material read along the way is zeroized once the signer is built
+    pub fn load(&self) -> Result<PrivateKeySigner, CliError> {
+        self.check_sources()?;
+        if !self.mnemonic && (self.mnemonic_index.is_some() || self.derivation_path.is_some()) {
+            return Err(CliError::InvalidInput(
+                "A mnemonic index or derivation path requires --mnemonic".to_string(),
+            ));
+        }
+
+        if let Some(private_key) = self.read_private_key()? {
+            return private_key.trim().parse().map_err(|e| {
+                CliError::InvalidInput(format!("Failed to parse private key: {}", e))
+            });
+        }
+        if let Some(account) = &self.account {
+            let account = resolve_account(account)?;
+            let password = Zeroizing::new(rpassword::prompt_password("Enter keystore password:")?);
+            return PrivateKeySigner::decrypt_keystore(account, password.as_str())
+                .map_err(|e| CliError::InvalidInput(format!("Failed to unlock keystore: {}", e)));
+        }
+        if self.mnemonic {
+            return self.derive_from_mnemonic();
+        }
+        Err(CliError::InvalidInput(
+            "A signing key must be provided (--key, --key-env, --key-file, --key-stdin, --account, --mnemonic or --remote-signer)"
+                .to_string(),
+        ))
+    }
+
+    fn check_sources(&self) -> Result<(), CliError> {
+        if self.sources() > 1 {
+            return Err(CliError::InvalidInput(
+                "Only one of --key, --key-env, --key-file, --key-stdin, --account, --mnemonic or --remote-signer can be provided"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The raw private key from the command line, an environment variable, a file or stdin
+    fn read_private_key(&self) -> Result<Option<Zeroizing<String>>, CliError> {
+        if let Some(private_key) = &self.private_key {
+            return Ok(Some(private_key.clone()));
+        }
+        if let Some(var) = &self.key_env {
+            return std::env::var(var)
+                .map(|key| Some(Zeroizing::new(key)))
+                .map_err(|_| {
+                    CliError::InvalidInput(format!("Environment variable {} is not set", var))
+                });
+        }
+        if let Some(path) = &self.key_file {
+            let read_error = |e: std::io::Error| {
+                CliError::InvalidInput(format!("Failed to read key file {}: {}", path.display(), e))
+            };
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = fs::metadata(path).map_err(read_error)?.permissions().mode();
+                if mode & 0o004 != 0 {
+                    return Err(CliError::InvalidInput(format!(
+                        "Key file {} is readable by every user (mode {:o}); restrict it with `chmod 600`",
+                        path.display(),
+                        mode & 0o777
+                    )));
+                }
+            }
+            let contents = Zeroizing::new(fs::read_to_string(path).map_err(read_error)?);
+            return Ok(Some(Zeroizing::new(
+                contents.lines().next().unwrap_or_default().to_string(),
+            )));
+        }
+        if self.key_stdin {
+            let mut line = Zeroizing::new(String::new());
+            std::io::stdin().read_line(&mut line)?;
+            return Ok(Some(line));
+        }
+        Ok(None)
+    }
+
+    fn derive_from_mnemonic(&self) -> Result<PrivateKeySigner, CliError> {
+        let phrase = Zeroizing::new(rpassword::prompt_password("Enter mnemonic:")?);
+        // Normalize the whitespace of a pasted phrase
+        let phrase = Zeroizing::new(phrase.split_whitespace().collect::<Vec<_>>().join(" "));
+        let builder = MnemonicBuilder::<English>::default().phrase(phrase.as_str());
+        let builder = match (&self.derivation_path, self.mnemonic_index) {
+            (Some(_), Some(_)) => {
+                return Err(CliError::InvalidInput(
+                    "Only one of mnemonic index or derivation path can be provided".to_string(),
+                ))
+            }
+            (Some(path), None) => builder.derivation_path(path),
+            (None, index) => builder.index(index.unwrap_or(0)),
+        }
+        .map_err(|e| CliError::InvalidInput(format!("Invalid derivation path: {}", e)))?;
+        builder
+            .build()
+            .map_err(|e| CliError::InvalidInput(format!("Invalid mnemonic: {}", e)))
+    }
+}
+
+/// Signer delegating to an external JSON-RPC signer (e.g. Clef or web3signer) through
+/// eth_signTransaction, so the private key never enters this process
+#[derive(Clone)]
+pub struct RemoteSigner {
+    client: RpcClient<Http<Client>>,
+    address: Address,
+}
+
+impl RemoteSigner {
+    /// Connect to the signer at `url`, using its first account unless `address` is given
+    pub async fn connect(url: &str, address: Option<Address>) -> Result<Self, CliError> {
+        let client = ClientBuilder::default().http(parse_url(url)?);
+        let address = match address {
+            Some(address) => address,
+            None => {
+                let accounts: Vec<Address> = client.request_noparams("eth_accounts").await?;
+                *accounts.first().ok_or_else(|| {
+                    CliError::InvalidInput(format!("Remote signer {} has no accounts", url))
+                })?
+            }
+        };
+        Ok(RemoteSigner { client, address })
+    }
+}
+
+#[async_trait]
+impl TxSigner<PrimitiveSignature> for RemoteSigner {
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    async fn sign_transaction(
+        &self,
+        tx: &mut dyn SignableTransaction<PrimitiveSignature>,
+    ) -> alloy::signers::Result<PrimitiveSignature> {
+        let mut request = json!({
+            "from": self.address,
+            "to": tx.to(),
+            "gas": format!("{:#x}", tx.gas_limit()),
+            "value": tx.value(),
+            "data": tx.input(),
+            "input": tx.input(),
+            "nonce": format!("{:#x}", tx.nonce()),
+        });
+        if let Some(chain_id) = tx.chain_id() {
+            request["chainId"] = json!(format!("{:#x}", chain_id));
+        }
+        match tx.max_priority_fee_per_gas() {
+            Some(priority_fee) => {
+                request["maxFeePerGas"] = json!(format!("{:#x}", tx.max_fee_per_gas()));
+                request["maxPriorityFeePerGas"] = json!(format!("{:#x}", priority_fee));
+            }
+            None => request["gasPrice"] = json!(format!("{:#x}", tx.max_fee_per_gas())),
+        }
+
+        // Signers answer with the raw transaction, or with an object holding it (Clef)
+        let response: Value = self
+            .client
+            .request("eth_signTransaction", (request,))
+            .await
+            .map_err(alloy::signers::Error::other)?;
+        let raw = match &response {
+            Value::Object(object) => object.get("raw").cloned().unwrap_or(Value::Null),
+            raw => raw.clone(),
+        };
+        let raw: Bytes = serde_json::from_value(raw).map_err(|_| {
+            alloy::signers::Error::other(format!(
+                "Unexpected eth_signTransaction response: {}",
+                response
+            ))
+        })?;
+        let envelope = TxEnvelope::decode_2718(&mut raw.as_ref()).map_err(|e| {
+            alloy::signers::Error::other(format!("Invalid signed transaction: {}", e))
+        })?;
+
+        // Only accept a signature over exactly the requested transaction, by the expected account
+        if envelope.signature_hash() != tx.signature_hash() {
+            return Err(alloy::signers::Error::other(
+                "The remote signer signed a different transaction than requested",
+            ));
+        }
+        let signer = envelope.recover_signer()?;
+        if signer != self.address {
+            return Err(alloy::signers::Error::other(format!(
+                "The remote signer signed with {} instead of {}",
+                signer, self.address
+            )));
+        }
+        Ok(*envelope.signature())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::{
+        consensus::TxEip1559,
+        eips::eip2718::Encodable2718,
+        network::TransactionBuilder,
+        primitives::{TxKind, U256},
+        rpc::types::TransactionRequest,
+    };
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// How the stand-in signer answers eth_signTransaction
+    #[derive(Clone, Copy)]
+    enum Behavior {
+        Honest,
+        ChangesNonce,
+        WrongKey,
+    }
+
+    /// Serve a minimal JSON-RPC signer over HTTP that lists `account` in eth_accounts and signs
+    /// with it (unless told to misbehave), returning its URL
+    async fn stand_in(account: PrivateKeySigner, behavior: Behavior) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let account = account.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    loop {
+                        // Headers up to the blank line, then a body of Content-Length bytes
+                        let mut length = 0;
+                        loop {
+                            let mut line = String::new();
+                            if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                                return;
+                            }
+                            if line == "\r\n" {
+                                break;
+                            }
+                            if let Some((name, value)) = line.split_once(':') {
+                                if name.eq_ignore_ascii_case("content-length") {
+                                    length = value.trim().parse().unwrap();
+                                }
+                            }
+                        }
+                        let mut body = vec![0; length];
+                        stream.read_exact(&mut body).await.unwrap();
+                        let request: Value = serde_json::from_slice(&body).unwrap();
+                        let response = json!({
+                            "jsonrpc": "2.0",
+                            "id": request["id"],
+                            "result": answer(&account, behavior, &request).await,
+                        })
+                        .to_string();
+                        let http = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                            response.len(),
+                            response
+                        );
+                        stream.get_mut().write_all(http.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        url
+    }
+
+    async fn answer(account: &PrivateKeySigner, behavior: Behavior, request: &Value) -> Value {
+        match request["method"].as_str() {
+            Some("eth_accounts") => json!([account.address()]),
+            Some("eth_signTransaction") => {
+                let mut tx: TransactionRequest =
+                    serde_json::from_value(request["params"][0].clone()).unwrap();
+                let key = match behavior {
+                    Behavior::WrongKey => PrivateKeySigner::random(),
+                    _ => account.clone(),
+                };
+                if let Behavior::ChangesNonce = behavior {
+                    tx.nonce = tx.nonce.map(|nonce| nonce + 1);
+                }
+                tx.from = Some(key.address());
+                let envelope = tx.build(&EthereumWallet::from(key)).await.unwrap();
+                json!(Bytes::from(envelope.encoded_2718()))
+            }
+            method => panic!("unexpected method {:?}", method),
+        }
+    }
+
+    fn purchase() -> TxEip1559 {
+        TxEip1559 {
+            chain_id: 17000,
+            nonce: 3,
+            gas_limit: 100_000,
+            max_fee_per_gas: 2_000_000_000,
+            max_priority_fee_per_gas: 1_000_000_000,
+            to: TxKind::Call(Address::repeat_byte(0x11)),
+            value: U256::from(1_000_000u64),
+            input: Bytes::from_static(&[0xde, 0xad, 0xbe, 0xef]),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn remote_signer_signs_the_requested_transaction() {
+        let account = PrivateKeySigner::random();
+        let url = stand_in(account.clone(), Behavior::Honest).await;
+        let signer = RemoteSigner::connect(&url, None).await.unwrap();
+        assert_eq!(TxSigner::address(&signer), account.address());
+
+        let mut tx = purchase();
+        let signature = signer.sign_transaction(&mut tx).await.unwrap();
+        assert_eq!(
+            signature
+                .recover_address_from_prehash(&tx.signature_hash())
+                .unwrap(),
+            account.address()
+        );
+    }
+
+    #[tokio::test]
+    async fn remote_signer_rejects_a_different_transaction() {
+        let url = stand_in(PrivateKeySigner::random(), Behavior::ChangesNonce).await;
+        let signer = RemoteSigner::connect(&url, None).await.unwrap();
+
+        let error = signer.sign_transaction(&mut purchase()).await.unwrap_err();
+        assert!(
+            error.to_string().contains("signed a different transaction"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn remote_signer_rejects_the_wrong_signer() {
+        let account = PrivateKeySigner::random();
+        let url = stand_in(account.clone(), Behavior::WrongKey).await;
+        let signer = RemoteSigner::connect(&url, Some(account.address()))
+            .await
+            .unwrap();
+
+        let error = signer.sign_transaction(&mut purchase()).await.unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains(&format!("instead of {}", account.address())),
+            "{}",
+            error
+        );
+    }
+}
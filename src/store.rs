@@ -0,0 +1,1122 @@
+use crate::commands::utils::{parse_json_file, read_input_file};
+use crate::encryption::{Cipher, EncryptedFile, Passphrase};
+use crate::error::CliError;
+use alloy::{
+    hex,
+    primitives::{Address, FixedBytes, TxHash},
+};
+use eth_stealth_gas_tickets::{BlindedSignature, SignedTicket, UnsignedTicket};
+use rand::{thread_rng, RngCore};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const STORE_FILE: &str = "wallet.json";
+
+/// Local wallet of ticket batches, persisted as a single JSON document in ~/.stealthereum
+/// (optionally encrypted with a passphrase).
+///
+/// Several commands may have the wallet open at once (e.g. `finalize --watch` next to `buy`),
+/// so every save reloads the file under a lock and only writes back the tickets this instance
+/// changed since it last read or wrote it
+#[derive(Serialize, Deserialize, Default)]
+pub struct TicketStore {
+    #[serde(skip)]
+    path: PathBuf,
+    /// Key the next save encrypts with
+    #[serde(skip)]
+    cipher: Option<Cipher>,
+    /// Key the file on disk was last read or written with
+    #[serde(skip)]
+    disk_cipher: Option<Cipher>,
+    #[serde(skip)]
+    passphrase: Passphrase,
+    /// History length of every ticket as last read from or written to disk, by batch and id
+    #[serde(skip)]
+    synced: HashMap<(String, FixedBytes<32>), usize>,
+    pub batches: Vec<Batch>,
+    #[serde(default)]
+    pub scan_checkpoints: Vec<ScanCheckpoint>,
+}
+
+/// Highest block already scanned for SendGasTickets events on a StealthGasStation contract
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ScanCheckpoint {
+    pub chain_id: u64,
+    pub contract: Address,
+    pub scanned_to: u64,
+}
+
+/// A set of tickets generated together for one chain and coordinator pubkey
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Batch {
+    pub id: String,
+    pub chain_id: u64,
+    pub pubkey: String,
+    pub created_at: u64,
+    pub tickets: Vec<StoredTicket>,
+}
+
+/// An unsigned ticket together with its lifecycle state and finalized counterpart (once known)
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StoredTicket {
+    pub unsigned: UnsignedTicket,
+    #[serde(default)]
+    pub state: TicketState,
+    #[serde(default)]
+    pub history: Vec<StateChange>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blind_signature: Option<BlindedSignature>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signed: Option<SignedTicket>,
+}
+
+/// Lifecycle of a ticket, from local generation to being spent through the coordinator
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum TicketState {
+    #[default]
+    Generated,
+    PurchaseTxPending,
+    Purchased,
+    BlindSignatureReceived,
+    Finalized,
+    RedemptionSubmitted,
+    Spent,
+    Failed,
+}
+
+/// A recorded state transition, with the transaction and block that caused it (if onchain)
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StateChange {
+    pub state: TicketState,
+    pub timestamp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tx_hash: Option<TxHash>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block: Option<u64>,
+}
+
+impl TicketState {
+    pub const ALL: [TicketState; 8] = [
+        TicketState::Generated,
+        TicketState::PurchaseTxPending,
+        TicketState::Purchased,
+        TicketState::BlindSignatureReceived,
+        TicketState::Finalized,
+        TicketState::RedemptionSubmitted,
+        TicketState::Spent,
+        TicketState::Failed,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TicketState::Generated => "generated",
+            TicketState::PurchaseTxPending => "purchase-tx-pending",
+            TicketState::Purchased => "purchased",
+            TicketState::BlindSignatureReceived => "blind-signature-received",
+            TicketState::Finalized => "finalized",
+            TicketState::RedemptionSubmitted => "redemption-submitted",
+            TicketState::Spent => "spent",
+            TicketState::Failed => "failed",
+        }
+    }
+
+    /// Tickets can be (re)bought if they were never purchased or their purchase failed
+    pub fn is_purchasable(&self) -> bool {
+        matches!(self, TicketState::Generated | TicketState::Failed)
+    }
+
+    /// Tickets that may still receive a blind signature from the coordinator
+    pub fn is_awaiting_signature(&self) -> bool {
+        *self < TicketState::Finalized
+    }
+
+    /// Bought (or being bought) tickets for which the coordinator has not sent a blind signature yet
+    pub fn is_awaiting_coordinator(&self) -> bool {
+        matches!(
+            self,
+            TicketState::PurchaseTxPending | TicketState::Purchased
+        )
+    }
+
+    /// Bought tickets that finalize still has to move on: awaiting the coordinator's blind
+    /// signature, or holding one that is not finalized yet
+    pub fn is_awaiting_finalization(&self) -> bool {
+        self.is_awaiting_coordinator() || *self == TicketState::BlindSignatureReceived
+    }
+
+    /// Tickets that hold a finalized signature (whether or not they were spent since)
+    pub fn is_finalized(&self) -> bool {
+        matches!(
+            self,
+            TicketState::Finalized | TicketState::RedemptionSubmitted | TicketState::Spent
+        )
+    }
+
+    /// Whether this state can only be reached from `earlier`, not the other way around
+    fn follows(&self, earlier: TicketState) -> bool {
+        earlier.can_transition_to(*self) && !self.can_transition_to(earlier)
+    }
+
+    fn can_transition_to(&self, next: TicketState) -> bool {
+        match (self, next) {
+            // a failed purchase can be retried, a stuck one replaced, a redemption of unknown
+            // outcome resent, and a rejected redemption leaves the ticket spendable
+            (TicketState::Failed, TicketState::PurchaseTxPending) => true,
+            (TicketState::PurchaseTxPending, TicketState::PurchaseTxPending) => true,
+            (TicketState::RedemptionSubmitted, TicketState::RedemptionSubmitted) => true,
+            (TicketState::RedemptionSubmitted, TicketState::Finalized) => true,
+            (TicketState::Spent, _) | (_, TicketState::Generated) => false,
+            (current, TicketState::Failed) => *current < TicketState::Finalized,
+            (TicketState::Failed, _) => false,
+            (current, next) => next > *current,
+        }
+    }
+}
+
+impl std::fmt::Display for TicketState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl StoredTicket {
+    fn new(unsigned: UnsignedTicket) -> Self {
+        Self {
+            unsigned,
+            state: TicketState::Generated,
+            history: vec![StateChange {
+                state: TicketState::Generated,
+                timestamp: now(),
+                tx_hash: None,
+                block: None,
+            }],
+            blind_signature: None,
+            signed: None,
+        }
+    }
+
+    /// Move the ticket to a new lifecycle state, recording when and by which tx/block it happened
+    pub fn transition(
+        &mut self,
+        state: TicketState,
+        tx_hash: Option<TxHash>,
+        block: Option<u64>,
+    ) -> Result<(), CliError> {
+        if !self.state.can_transition_to(state) {
+            return Err(CliError::InvalidInput(format!(
+                "Ticket {} cannot move from {} to {}",
+                self.unsigned.id, self.state, state
+            )));
+        }
+        self.state = state;
+        self.history.push(StateChange {
+            state,
+            timestamp: now(),
+            tx_hash,
+            block,
+        });
+        Ok(())
+    }
+
+    /// The transaction that moved the ticket into `state`, if any
+    pub fn tx_for(&self, state: TicketState) -> Option<TxHash> {
+        self.history
+            .iter()
+            .rev()
+            .find(|c| c.state == state)
+            .and_then(|c| c.tx_hash)
+    }
+
+    /// Pending through a call exported with `buy calldata`, which has no transaction to follow
+    /// until the smart contract wallet executes it
+    pub fn is_exported_purchase(&self) -> bool {
+        self.state == TicketState::PurchaseTxPending
+            && self.tx_for(TicketState::PurchaseTxPending).is_none()
+    }
+
+    /// Every transaction of the pending purchase, oldest first: the original and the
+    /// `tx speedup` / `tx cancel` replacements sent for it (only one of them can be mined)
+    pub fn pending_purchase_txs(&self) -> Vec<TxHash> {
+        if self.state != TicketState::PurchaseTxPending {
+            return Vec::new();
+        }
+        let mut txs: Vec<TxHash> = self
+            .history
+            .iter()
+            .rev()
+            .take_while(|c| c.state == TicketState::PurchaseTxPending)
+            .filter_map(|c| c.tx_hash)
+            .collect();
+        txs.reverse();
+        txs
+    }
+}
+
+impl Batch {
+    /// Unsigned tickets whose state is accepted by `usable`
+    pub fn unsigned_tickets(&self, usable: impl Fn(TicketState) -> bool) -> Vec<UnsignedTicket> {
+        self.tickets
+            .iter()
+            .filter(|t| usable(t.state))
+            .map(|t| t.unsigned.clone())
+            .collect()
+    }
+
+    /// Finalized tickets whose state is accepted by `usable`
+    pub fn signed_tickets(&self, usable: impl Fn(TicketState) -> bool) -> Vec<SignedTicket> {
+        self.tickets
+            .iter()
+            .filter(|t| usable(t.state))
+            .filter_map(|t| t.signed.clone())
+            .collect()
+    }
+
+    /// Inclusion block of the (earliest) purchase of the tickets still waiting for a blind signature
+    pub fn purchase_block(&self) -> Option<u64> {
+        self.tickets
+            .iter()
+            .filter(|t| t.state.is_awaiting_signature() && t.blind_signature.is_none())
+            .filter_map(|t| {
+                t.history
+                    .iter()
+                    .rev()
+                    .find(|c| c.state == TicketState::Purchased)
+                    .and_then(|c| c.block)
+            })
+            .min()
+    }
+
+    pub fn count(&self, usable: impl Fn(TicketState) -> bool) -> usize {
+        self.tickets.iter().filter(|t| usable(t.state)).count()
+    }
+
+    pub fn ticket_mut(&mut self, id: &FixedBytes<32>) -> Option<&mut StoredTicket> {
+        self.tickets.iter_mut().find(|t| t.unsigned.id == *id)
+    }
+
+    pub fn contains(&self, id: &FixedBytes<32>) -> bool {
+        self.tickets.iter().any(|t| t.unsigned.id == *id)
+    }
+}
+
+impl TicketStore {
+    /// Open the wallet in the default location, creating an empty one if none exists
+    pub fn open(passphrase: &Passphrase) -> Result<Self, CliError> {
+        let path = crate::commands::utils::get_stealth_dir()?.join(STORE_FILE);
+        Self::open_at(&path, passphrase)
+    }
+
+    /// Open a wallet, asking for the passphrase only if it is encrypted
+    pub fn open_at(path: &Path, passphrase: &Passphrase) -> Result<Self, CliError> {
+        if !path.exists() {
+            return Ok(TicketStore {
+                path: path.to_path_buf(),
+                passphrase: passphrase.clone(),
+                ..Default::default()
+            });
+        }
+
+        let (mut store, cipher) = Self::read(path, passphrase, None)?;
+        store.path = path.to_path_buf();
+        store.cipher = cipher.clone();
+        store.disk_cipher = cipher;
+        store.passphrase = passphrase.clone();
+        store.mark_synced();
+        Ok(store)
+    }
+
+    /// Read and decrypt the wallet file, reusing `known` if the file is encrypted under it
+    fn read(
+        path: &Path,
+        passphrase: &Passphrase,
+        known: Option<&Cipher>,
+    ) -> Result<(TicketStore, Option<Cipher>), CliError> {
+        let contents = fs::read_to_string(path)?;
+        let Some(encrypted) = EncryptedFile::parse(&contents) else {
+            let store = serde_json::from_str(&contents).map_err(|e| corrupt(path, e))?;
+            return Ok((store, None));
+        };
+        let (cipher, plaintext) = match known {
+            Some(cipher) if cipher.matches(&encrypted) => {
+                (cipher.clone(), cipher.decrypt(&encrypted)?)
+            }
+            _ => encrypted.unlock(&passphrase.resolve("Enter wallet passphrase:")?)?,
+        };
+        let store = serde_json::from_slice(&plaintext).map_err(|e| corrupt(path, e))?;
+        Ok((store, Some(cipher)))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Encrypt the wallet (and future exports) with a new passphrase, or store it in plaintext
+    /// with None. Takes effect on the next save
+    pub fn set_passphrase(&mut self, passphrase: Option<&str>) -> Result<(), CliError> {
+        self.cipher = passphrase.map(Cipher::new).transpose()?;
+        Ok(())
+    }
+
+    /// Persist the wallet, writing to a temporary file first so a crash never leaves it truncated.
+    /// Changes other commands saved in the meantime are merged in (and become visible here),
+    /// including a passphrase they set, unless this instance is changing the passphrase too
+    pub fn save(&mut self) -> Result<(), CliError> {
+        let _lock = self.lock()?;
+        if self.path.exists() {
+            let (saved, found) =
+                Self::read(&self.path, &self.passphrase, self.disk_cipher.as_ref())?;
+            if found != self.disk_cipher {
+                if self.cipher != self.disk_cipher {
+                    return Err(CliError::Storage(format!(
+                        "Another command changed the passphrase of {} in the meantime; run the command again to change it",
+                        self.path.display()
+                    )));
+                }
+                self.cipher = found;
+            }
+            self.merge_saved(saved);
+        }
+        write_private(
+            &self.path,
+            &self.seal(&serde_json::to_string_pretty(self)?)?,
+        )?;
+        self.disk_cipher = self.cipher.clone();
+        self.mark_synced();
+        Ok(())
+    }
+
+    /// Hold an exclusive lock on the wallet until the returned file is dropped
+    fn lock(&self) -> Result<fs::File, CliError> {
+        let mut lock_path = self.path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let mut options = fs::OpenOptions::new();
+        options.create(true).truncate(false).write(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options.open(&lock_path)?;
+        file.lock().map_err(|e| {
+            CliError::Storage(format!("Failed to lock {}: {}", self.path.display(), e))
+        })?;
+        Ok(file)
+    }
+
+    /// Take the wallet as saved on disk, except for the batches and tickets changed here since
+    /// the last read or write (every change to a ticket appends to its history). A ticket changed
+    /// both here and on disk keeps whichever state comes later in its lifecycle
+    fn merge_saved(&mut self, saved: TicketStore) {
+        let mut ours = std::mem::take(&mut self.batches);
+        let mut merged = Vec::with_capacity(saved.batches.len());
+        for mut batch in saved.batches {
+            if let Some(i) = ours.iter().position(|b| b.id == batch.id) {
+                let mine = ours.remove(i);
+                for ticket in mine.tickets {
+                    let key = (mine.id.clone(), ticket.unsigned.id);
+                    if self.synced.get(&key) == Some(&ticket.history.len()) {
+                        continue;
+                    }
+                    match batch.ticket_mut(&ticket.unsigned.id) {
+                        Some(saved_ticket) if saved_ticket.state.follows(ticket.state) => {}
+                        Some(saved_ticket) => *saved_ticket = ticket,
+                        None => batch.tickets.push(ticket),
+                    }
+                }
+            }
+            merged.push(batch);
+        }
+        // Batches created here are the newest
+        merged.append(&mut ours);
+        self.batches = merged;
+
+        for checkpoint in saved.scan_checkpoints {
+            self.set_checkpoint(
+                checkpoint.chain_id,
+                &checkpoint.contract,
+                checkpoint.scanned_to,
+            );
+        }
+    }
+
+    fn mark_synced(&mut self) {
+        self.synced = self
+            .batches
+            .iter()
+            .flat_map(|b| {
+                b.tickets
+                    .iter()
+                    .map(|t| ((b.id.clone(), t.unsigned.id), t.history.len()))
+            })
+            .collect();
+    }
+
+    /// Read a JSON file, decrypting it if it is an encrypted export
+    pub fn read_json_file<T: DeserializeOwned>(&self, path: &str) -> Result<T, CliError> {
+        let contents = read_input_file(path)?;
+        let Some(encrypted) = EncryptedFile::parse(&contents) else {
+            return parse_json_file(path, contents.as_bytes());
+        };
+        // Exports made since the wallet passphrase was last set share its key
+        let plaintext = match &self.cipher {
+            Some(cipher) if cipher.matches(&encrypted) => cipher.decrypt(&encrypted)?,
+            _ => {
+                let prompt = format!("Enter passphrase for {}:", path);
+                encrypted.unlock(&self.passphrase.resolve(&prompt)?)?.1
+            }
+        };
+        parse_json_file(path, &plaintext)
+    }
+
+    /// Write a JSON export, encrypted with the wallet passphrase if the wallet is encrypted
+    pub fn write_json_file<T: Serialize>(&self, path: &str, value: &T) -> Result<(), CliError> {
+        write_private(
+            Path::new(path),
+            &self.seal(&serde_json::to_string_pretty(value)?)?,
+        )
+    }
+
+    fn seal(&self, json: &str) -> Result<String, CliError> {
+        match &self.cipher {
+            Some(cipher) => Ok(serde_json::to_string_pretty(
+                &cipher.encrypt(json.as_bytes())?,
+            )?),
+            None => Ok(json.to_string()),
+        }
+    }
+
+    /// Add a new batch of freshly generated tickets and return its id
+    pub fn add_batch(
+        &mut self,
+        chain_id: u64,
+        pubkey: &str,
+        tickets: Vec<UnsignedTicket>,
+    ) -> Result<String, CliError> {
+        let pubkey = normalize_pubkey(pubkey);
+        for ticket in &tickets {
+            if self
+                .batches
+                .iter()
+                .any(|b| b.chain_id == chain_id && b.pubkey == pubkey && b.contains(&ticket.id))
+            {
+                return Err(CliError::InvalidInput(format!(
+                    "Ticket {} is already stored",
+                    ticket.id
+                )));
+            }
+        }
+
+        let mut id_bytes = [0u8; 4];
+        thread_rng().fill_bytes(&mut id_bytes);
+        let id = hex::encode(id_bytes);
+
+        self.batches.push(Batch {
+            id: id.clone(),
+            chain_id,
+            pubkey,
+            created_at: now(),
+            tickets: tickets.into_iter().map(StoredTicket::new).collect(),
+        });
+
+        Ok(id)
+    }
+
+    /// Import tickets loaded from a JSON file, reusing the batch that already holds them if any
+    pub fn import_batch(
+        &mut self,
+        chain_id: u64,
+        pubkey: &str,
+        tickets: Vec<UnsignedTicket>,
+    ) -> Result<String, CliError> {
+        // Every batch holds an empty set of tickets, so there is nothing to match or import
+        if tickets.is_empty() {
+            return Err(CliError::InvalidInput("No tickets to import".to_string()));
+        }
+        let normalized = normalize_pubkey(pubkey);
+        if let Some(batch) = self.batches.iter().find(|b| {
+            b.chain_id == chain_id
+                && b.pubkey == normalized
+                && tickets.iter().all(|t| b.contains(&t.id))
+        }) {
+            return Ok(batch.id.clone());
+        }
+        self.add_batch(chain_id, pubkey, tickets)
+    }
+
+    /// Look up a batch by id, or the most recent batch for the chain (and pubkey, if given)
+    /// that has at least one ticket in a state accepted by `usable`
+    pub fn select_batch(
+        &self,
+        batch_id: Option<&str>,
+        chain_id: u64,
+        pubkey: Option<&str>,
+        usable: impl Fn(TicketState) -> bool,
+    ) -> Result<&Batch, CliError> {
+        self.select_batch_by_ticket(batch_id, chain_id, pubkey, |t| usable(t.state))
+    }
+
+    /// Like `select_batch`, for a `usable` that looks at more of a ticket than its state
+    pub fn select_batch_by_ticket(
+        &self,
+        batch_id: Option<&str>,
+        chain_id: u64,
+        pubkey: Option<&str>,
+        usable: impl Fn(&StoredTicket) -> bool,
+    ) -> Result<&Batch, CliError> {
+        let pubkey = pubkey.map(normalize_pubkey);
+        let batch = match batch_id {
+            Some(id) => self.batch(id)?,
+            None => self
+                .batches
+                .iter()
+                .rev()
+                .find(|b| {
+                    b.chain_id == chain_id
+                        && pubkey.as_ref().is_none_or(|p| b.pubkey == *p)
+                        && b.tickets.iter().any(&usable)
+                })
+                .ok_or_else(|| {
+                    CliError::InvalidInput(format!(
+                        "No usable ticket batches for chain {} in {}",
+                        chain_id,
+                        self.path.display()
+                    ))
+                })?,
+        };
+        if batch.chain_id != chain_id {
+            return Err(CliError::InvalidInput(format!(
+                "Batch {} belongs to chain {}, not chain {}",
+                batch.id, batch.chain_id, chain_id
+            )));
+        }
+        Ok(batch)
+    }
+
+    pub fn batch(&self, batch_id: &str) -> Result<&Batch, CliError> {
+        self.batches
+            .iter()
+            .find(|b| b.id == batch_id)
+            .ok_or_else(|| self.missing_batch(batch_id))
+    }
+
+    pub fn batch_mut(&mut self, batch_id: &str) -> Result<&mut Batch, CliError> {
+        let error = self.missing_batch(batch_id);
+        self.batches
+            .iter_mut()
+            .find(|b| b.id == batch_id)
+            .ok_or(error)
+    }
+
+    fn missing_batch(&self, batch_id: &str) -> CliError {
+        CliError::InvalidInput(format!(
+            "Batch {} not found in {}",
+            batch_id,
+            self.path.display()
+        ))
+    }
+
+    /// Ids of every ticket on the chain still waiting for a blind signature, across all batches
+    pub fn awaiting_signature_ids(&self, chain_id: u64) -> HashSet<FixedBytes<32>> {
+        self.batches
+            .iter()
+            .filter(|b| b.chain_id == chain_id)
+            .flat_map(|b| b.tickets.iter())
+            .filter(|t| t.state.is_awaiting_signature() && t.blind_signature.is_none())
+            .map(|t| t.unsigned.id)
+            .collect()
+    }
+
+    pub fn ticket_mut(&mut self, chain_id: u64, id: &FixedBytes<32>) -> Option<&mut StoredTicket> {
+        self.batches
+            .iter_mut()
+            .filter(|b| b.chain_id == chain_id)
+            .find_map(|b| b.ticket_mut(id))
+    }
+
+    pub fn checkpoint(&self, chain_id: u64, contract: &Address) -> Option<u64> {
+        self.scan_checkpoints
+            .iter()
+            .find(|c| c.chain_id == chain_id && c.contract == *contract)
+            .map(|c| c.scanned_to)
+    }
+
+    /// Record that every block up to `scanned_to` has been scanned (checkpoints never move back)
+    pub fn set_checkpoint(&mut self, chain_id: u64, contract: &Address, scanned_to: u64) {
+        match self
+            .scan_checkpoints
+            .iter_mut()
+            .find(|c| c.chain_id == chain_id && c.contract == *contract)
+        {
+            Some(checkpoint) => checkpoint.scanned_to = checkpoint.scanned_to.max(scanned_to),
+            None => self.scan_checkpoints.push(ScanCheckpoint {
+                chain_id,
+                contract: *contract,
+                scanned_to,
+            }),
+        }
+    }
+
+    /// Earliest inclusion block of the purchases of tickets still waiting for a blind signature
+    pub fn earliest_purchase_block(&self, chain_id: u64) -> Option<u64> {
+        self.batches
+            .iter()
+            .filter(|b| b.chain_id == chain_id)
+            .filter_map(|b| b.purchase_block())
+            .min()
+    }
+
+    /// Find the stored ticket a finalized ticket was produced from
+    pub fn find_signed_mut(&mut self, signed: &SignedTicket) -> Option<&mut StoredTicket> {
+        self.batches
+            .iter_mut()
+            .flat_map(|b| b.tickets.iter_mut())
+            .find(|t| t.unsigned.msg == signed.msg)
+    }
+}
+
+/// Write a file readable only by the owner, through a temporary file so a crash never leaves
+/// it truncated
+fn write_private(path: &Path, contents: &str) -> Result<(), CliError> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn corrupt(path: &Path, e: serde_json::Error) -> CliError {
+    CliError::Storage(format!("Corrupt wallet {}: {}", path.display(), e))
+}
+
+pub fn normalize_pubkey(pubkey: &str) -> String {
+    format!("0x{}", pubkey.trim_start_matches("0x").to_lowercase())
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use alloy::primitives::Bytes;
+    use zeroize::Zeroizing;
+
+    pub(crate) const PUBKEY: &str = "0xABCD";
+
+    pub(crate) fn ticket(n: u8) -> UnsignedTicket {
+        UnsignedTicket {
+            msg: Bytes::from(vec![n]),
+            blind_msg: Bytes::from(vec![n, n]),
+            msg_randomizer: FixedBytes::repeat_byte(n),
+            id: FixedBytes::repeat_byte(n),
+            secret: Bytes::from(vec![n, n, n]),
+        }
+    }
+
+    #[test]
+    fn lifecycle_moves_forward() {
+        use TicketState::*;
+        let path = [
+            Generated,
+            PurchaseTxPending,
+            Purchased,
+            BlindSignatureReceived,
+            Finalized,
+            RedemptionSubmitted,
+            Spent,
+        ];
+        for pair in path.windows(2) {
+            assert!(
+                pair[0].can_transition_to(pair[1]),
+                "{} -> {}",
+                pair[0],
+                pair[1]
+            );
+            // Only a rejected redemption moves a ticket back
+            if pair != [Finalized, RedemptionSubmitted] {
+                assert!(
+                    !pair[1].can_transition_to(pair[0]),
+                    "{} -> {}",
+                    pair[1],
+                    pair[0]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn lifecycle_retries_and_dead_ends() {
+        use TicketState::*;
+        // Retries and replacements
+        assert!(Failed.can_transition_to(PurchaseTxPending));
+        assert!(PurchaseTxPending.can_transition_to(PurchaseTxPending));
+        assert!(RedemptionSubmitted.can_transition_to(RedemptionSubmitted));
+        assert!(RedemptionSubmitted.can_transition_to(Finalized));
+        // Purchases fail before the ticket is finalized, never after
+        assert!(PurchaseTxPending.can_transition_to(Failed));
+        assert!(Purchased.can_transition_to(Failed));
+        assert!(!Finalized.can_transition_to(Failed));
+        assert!(!Failed.can_transition_to(Purchased));
+        // Spent tickets and fresh tickets are final and initial
+        for state in TicketState::ALL {
+            assert!(!Spent.can_transition_to(state), "spent -> {}", state);
+            assert!(
+                !state.can_transition_to(Generated),
+                "{} -> generated",
+                state
+            );
+        }
+    }
+
+    #[test]
+    fn transition_records_history() {
+        let mut stored = StoredTicket::new(ticket(1));
+        let tx = TxHash::repeat_byte(7);
+        stored
+            .transition(TicketState::PurchaseTxPending, Some(tx), None)
+            .unwrap();
+        stored
+            .transition(TicketState::Purchased, Some(tx), Some(42))
+            .unwrap();
+        assert_eq!(stored.state, TicketState::Purchased);
+        assert_eq!(stored.history.len(), 3);
+        assert_eq!(stored.tx_for(TicketState::PurchaseTxPending), Some(tx));
+        assert_eq!(stored.tx_for(TicketState::Generated), None);
+
+        let error = stored.transition(TicketState::Generated, None, None);
+        assert!(matches!(error, Err(CliError::InvalidInput(_))));
+        assert_eq!(stored.state, TicketState::Purchased);
+        assert_eq!(stored.history.len(), 3);
+    }
+
+    #[test]
+    fn pending_purchase_keeps_every_replaced_tx() {
+        let mut stored = StoredTicket::new(ticket(1));
+        let (failed, original, replacement) = (
+            TxHash::repeat_byte(1),
+            TxHash::repeat_byte(2),
+            TxHash::repeat_byte(3),
+        );
+        stored
+            .transition(TicketState::PurchaseTxPending, Some(failed), None)
+            .unwrap();
+        stored
+            .transition(TicketState::Failed, Some(failed), Some(1))
+            .unwrap();
+        assert!(stored.pending_purchase_txs().is_empty());
+
+        for tx in [original, replacement] {
+            stored
+                .transition(TicketState::PurchaseTxPending, Some(tx), None)
+                .unwrap();
+        }
+        assert_eq!(stored.pending_purchase_txs(), vec![original, replacement]);
+        assert_eq!(
+            stored.tx_for(TicketState::PurchaseTxPending),
+            Some(replacement)
+        );
+    }
+
+    #[test]
+    fn exported_purchase_has_no_tx() {
+        let mut stored = StoredTicket::new(ticket(1));
+        stored
+            .transition(TicketState::PurchaseTxPending, None, None)
+            .unwrap();
+        assert!(stored.is_exported_purchase());
+        assert!(stored.pending_purchase_txs().is_empty());
+
+        stored
+            .transition(
+                TicketState::PurchaseTxPending,
+                Some(TxHash::repeat_byte(1)),
+                None,
+            )
+            .unwrap();
+        assert!(!stored.is_exported_purchase());
+    }
+
+    #[test]
+    fn import_reuses_the_batch_holding_the_tickets() {
+        let mut store = TicketStore::default();
+        let first = store
+            .add_batch(1, PUBKEY, vec![ticket(1), ticket(2)])
+            .unwrap();
+        assert_eq!(
+            store.import_batch(1, "abcd", vec![ticket(2)]).unwrap(),
+            first
+        );
+
+        // Other tickets, chains and pubkeys get their own batch
+        let second = store.import_batch(1, PUBKEY, vec![ticket(3)]).unwrap();
+        assert_ne!(second, first);
+        assert_ne!(
+            store.import_batch(2, PUBKEY, vec![ticket(1)]).unwrap(),
+            first
+        );
+        assert_ne!(
+            store.import_batch(1, "0x1234", vec![ticket(1)]).unwrap(),
+            first
+        );
+        assert_eq!(store.batches.len(), 4);
+    }
+
+    #[test]
+    fn import_rejects_empty_and_partly_stored_tickets() {
+        let mut store = TicketStore::default();
+        store.add_batch(1, PUBKEY, vec![ticket(1)]).unwrap();
+        assert!(store.import_batch(1, PUBKEY, Vec::new()).is_err());
+        assert!(store
+            .import_batch(1, PUBKEY, vec![ticket(1), ticket(2)])
+            .is_err());
+        assert_eq!(store.batches.len(), 1);
+    }
+
+    #[test]
+    fn select_batch_picks_the_most_recent_usable_batch() {
+        let mut store = TicketStore::default();
+        let old = store.add_batch(1, PUBKEY, vec![ticket(1)]).unwrap();
+        let new = store.add_batch(1, PUBKEY, vec![ticket(2)]).unwrap();
+        let other = store.add_batch(1, "0x1234", vec![ticket(3)]).unwrap();
+        store.add_batch(2, PUBKEY, vec![ticket(4)]).unwrap();
+
+        let purchasable = |s: TicketState| s.is_purchasable();
+        assert_eq!(
+            store.select_batch(None, 1, None, purchasable).unwrap().id,
+            other
+        );
+        assert_eq!(
+            store
+                .select_batch(None, 1, Some("abcd"), purchasable)
+                .unwrap()
+                .id,
+            new
+        );
+
+        // Batches without a usable ticket are skipped
+        store
+            .batch_mut(&new)
+            .unwrap()
+            .ticket_mut(&ticket(2).id)
+            .unwrap()
+            .transition(TicketState::PurchaseTxPending, None, None)
+            .unwrap();
+        assert_eq!(
+            store
+                .select_batch(None, 1, Some(PUBKEY), purchasable)
+                .unwrap()
+                .id,
+            old
+        );
+        assert!(store.select_batch(None, 3, None, purchasable).is_err());
+    }
+
+    #[test]
+    fn finalize_skips_a_newer_unbought_batch() {
+        let mut store = TicketStore::default();
+        let bought = store.add_batch(1, PUBKEY, vec![ticket(1)]).unwrap();
+        store
+            .batch_mut(&bought)
+            .unwrap()
+            .ticket_mut(&ticket(1).id)
+            .unwrap()
+            .transition(TicketState::PurchaseTxPending, None, None)
+            .unwrap();
+        store.add_batch(1, PUBKEY, vec![ticket(2)]).unwrap();
+
+        let finalizable = |s: TicketState| s.is_awaiting_finalization();
+        assert_eq!(
+            store
+                .select_batch(None, 1, Some(PUBKEY), finalizable)
+                .unwrap()
+                .id,
+            bought
+        );
+    }
+
+    #[test]
+    fn select_batch_checks_an_explicit_id() {
+        let mut store = TicketStore::default();
+        let id = store.add_batch(1, PUBKEY, vec![ticket(1)]).unwrap();
+        let any = |_: TicketState| true;
+        assert_eq!(store.select_batch(Some(&id), 1, None, any).unwrap().id, id);
+        assert!(store.select_batch(Some(&id), 2, None, any).is_err());
+        assert!(store.select_batch(Some("missing"), 1, None, any).is_err());
+    }
+
+    /// Empty wallet path in a fresh temporary directory
+    pub(crate) fn temp_wallet() -> PathBuf {
+        let mut name = [0u8; 8];
+        thread_rng().fill_bytes(&mut name);
+        let dir = std::env::temp_dir().join(format!("stealth-gas-test-{}", hex::encode(name)));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(STORE_FILE)
+    }
+
+    #[test]
+    fn concurrent_saves_keep_each_others_changes() {
+        let path = temp_wallet();
+        let passphrase = Passphrase::default();
+        let mut setup = TicketStore::open_at(&path, &passphrase).unwrap();
+        let shared = setup
+            .add_batch(1, PUBKEY, vec![ticket(1), ticket(2)])
+            .unwrap();
+        setup.save().unwrap();
+
+        // A long running command (e.g. finalize --watch) and a short one open the same wallet
+        let mut watcher = TicketStore::open_at(&path, &passphrase).unwrap();
+        let mut other = TicketStore::open_at(&path, &passphrase).unwrap();
+        let new = other.add_batch(1, PUBKEY, vec![ticket(3)]).unwrap();
+        other
+            .batch_mut(&shared)
+            .unwrap()
+            .ticket_mut(&ticket(2).id)
+            .unwrap()
+            .transition(TicketState::PurchaseTxPending, None, None)
+            .unwrap();
+        other.set_checkpoint(1, &Address::ZERO, 100);
+        other.save().unwrap();
+
+        watcher
+            .batch_mut(&shared)
+            .unwrap()
+            .ticket_mut(&ticket(1).id)
+            .unwrap()
+            .transition(TicketState::Failed, None, None)
+            .unwrap();
+        watcher.set_checkpoint(1, &Address::ZERO, 50);
+        watcher.save().unwrap();
+
+        for store in [watcher, TicketStore::open_at(&path, &passphrase).unwrap()] {
+            let batch = store.batch(&shared).unwrap();
+            assert_eq!(batch.tickets[0].state, TicketState::Failed);
+            assert_eq!(batch.tickets[1].state, TicketState::PurchaseTxPending);
+            assert_eq!(store.batch(&new).unwrap().tickets.len(), 1);
+            assert_eq!(store.checkpoint(1, &Address::ZERO), Some(100));
+        }
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn concurrent_changes_to_a_ticket_keep_the_later_state() {
+        let path = temp_wallet();
+        let passphrase = Passphrase::default();
+        let mut setup = TicketStore::open_at(&path, &passphrase).unwrap();
+        let id = setup
+            .add_batch(1, PUBKEY, vec![ticket(1), ticket(2)])
+            .unwrap();
+        setup.save().unwrap();
+
+        let mut behind = TicketStore::open_at(&path, &passphrase).unwrap();
+        let mut ahead = TicketStore::open_at(&path, &passphrase).unwrap();
+        let tx = Some(TxHash::repeat_byte(1));
+        for state in [TicketState::PurchaseTxPending, TicketState::Purchased] {
+            ahead
+                .batch_mut(&id)
+                .unwrap()
+                .ticket_mut(&ticket(1).id)
+                .unwrap()
+                .transition(state, tx, None)
+                .unwrap();
+        }
+        ahead.save().unwrap();
+
+        // The stale copy only got as far as the pending purchase, but is saved last
+        for n in [1, 2] {
+            behind
+                .batch_mut(&id)
+                .unwrap()
+                .ticket_mut(&ticket(n).id)
+                .unwrap()
+                .transition(TicketState::PurchaseTxPending, tx, None)
+                .unwrap();
+        }
+        behind.save().unwrap();
+
+        let batch = behind.batch(&id).unwrap();
+        assert_eq!(batch.tickets[0].state, TicketState::Purchased);
+        assert_eq!(batch.tickets[1].state, TicketState::PurchaseTxPending);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn save_keeps_a_passphrase_set_meanwhile() {
+        let path = temp_wallet();
+        let passphrase = Passphrase::Value(Zeroizing::new("correct horse".to_string()));
+        let mut setup = TicketStore::open_at(&path, &passphrase).unwrap();
+        let id = setup.add_batch(1, PUBKEY, vec![ticket(1)]).unwrap();
+        setup.save().unwrap();
+
+        // `finalize --watch` opens the plaintext wallet, then `wallet encrypt` runs
+        let mut watcher = TicketStore::open_at(&path, &passphrase).unwrap();
+        let mut encrypt = TicketStore::open_at(&path, &passphrase).unwrap();
+        encrypt.set_passphrase(Some("correct horse")).unwrap();
+        encrypt.save().unwrap();
+
+        watcher
+            .batch_mut(&id)
+            .unwrap()
+            .ticket_mut(&ticket(1).id)
+            .unwrap()
+            .transition(TicketState::Failed, None, None)
+            .unwrap();
+        watcher.save().unwrap();
+        assert!(watcher.is_encrypted());
+        assert!(EncryptedFile::parse(&fs::read_to_string(&path).unwrap()).is_some());
+        let reopened = TicketStore::open_at(&path, &passphrase).unwrap();
+        assert_eq!(
+            reopened.batch(&id).unwrap().tickets[0].state,
+            TicketState::Failed
+        );
+
+        // A passphrase change here conflicts with the one made meanwhile
+        let mut decrypt = TicketStore::open_at(&path, &passphrase).unwrap();
+        let mut rekey = TicketStore::open_at(&path, &passphrase).unwrap();
+        decrypt.set_passphrase(None).unwrap();
+        decrypt.save().unwrap();
+        rekey.set_passphrase(Some("battery staple")).unwrap();
+        assert!(matches!(rekey.save(), Err(CliError::Storage(_))));
+        assert!(EncryptedFile::parse(&fs::read_to_string(&path).unwrap()).is_none());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn checkpoints_never_move_back() {
+        let mut store = TicketStore::default();
+        let contract = Address::repeat_byte(1);
+        assert_eq!(store.checkpoint(1, &contract), None);
+        store.set_checkpoint(1, &contract, 100);
+        store.set_checkpoint(1, &contract, 50);
+        assert_eq!(store.checkpoint(1, &contract), Some(100));
+        store.set_checkpoint(1, &contract, 150);
+        assert_eq!(store.checkpoint(1, &contract), Some(150));
+
+        // Each chain and contract has its own checkpoint
+        store.set_checkpoint(2, &contract, 10);
+        store.set_checkpoint(1, &Address::repeat_byte(2), 20);
+        assert_eq!(store.checkpoint(1, &contract), Some(150));
+        assert_eq!(store.checkpoint(2, &contract), Some(10));
+    }
+}